@@ -21,6 +21,8 @@ for cmd in keytool javac javadoc jarsigner ; do
   need_cmd $cmd "Please be sure to have a working java installation" || exit $?
 done
 
-for cmd in android dx zipalign aapt; do
+for cmd in android aapt; do
   need_cmd $cmd "Consider running make init-<platform>" || exit $?
 done
+
+need_cmd d8 2>/dev/null || need_cmd dx "Either d8 or dx is required, consider running make init-<platform>" || exit $?
@@ -8,13 +8,20 @@ use std::collections::HashMap;
 use std::process::exit;
 use std::fs::File;
 use std::io::{self, Write, stderr};
-use clap::{App, Arg, SubCommand, ArgMatches};
+use clap::{App, Arg, SubCommand, ArgMatches, ErrorKind};
 use anders::scaffolding::{generate_application_scaffolding, CONTEXT_FILENAME};
 use anders::compile::{COMMAND_NAME as COMPILE_COMMAND, compile_application};
-use anders::package::{COMMAND_NAME as PACKAGE_COMMAND, package_application};
-use anders::launch::{COMMAND_NAME as LAUNCH_COMMAND, launch_application};
+use anders::package::{COMMAND_NAME as PACKAGE_COMMAND, package_application, apk_path, Profile};
+use anders::launch::{COMMAND_NAME as LAUNCH_COMMAND, DEFAULT_DEBUG_PORT, LaunchOptions,
+                     launch_application};
+use anders::logcat::{LogFilter, LogLine, Priority, stream_log, stdout_is_terminal};
+use anders::verify::{COMMAND_NAME as VERIFY_COMMAND, verify_application};
+use anders::doctor::{COMMAND_NAME as DOCTOR_COMMAND, VERSION_TIMEOUT_SECS, diagnose};
+use anders::{SpawningRunner, RecordingRunner, ReportingRunner, Verbosity, Event,
+             BatchExecutionError, DeviceSelector, Failure, fingerprint_file};
 use std::error::Error as StdError;
 use std::fmt::{self, Formatter, Display};
+use std::time::{Duration, Instant};
 
 use std::path::{Path, PathBuf};
 
@@ -47,21 +54,65 @@ quick_error! {
             display("Failed to interpret schema of context at '{}'", p.display())
             cause(err)
         }
+        ContextInvalid(p: PathBuf, err: anders::ContextVerificationError) {
+            description("The context file had invalid values")
+            display("The context at '{}' is invalid", p.display())
+            cause(err)
+        }
+        DryRunNotSupported(command: &'static str) {
+            description("--dry-run was given to a command running no programs")
+            display("'{}' runs no programs, so --dry-run doesn't apply to it", command)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageFormat {
+    Human,
+    /// Newline-delimited json events on stdout.
+    Json,
+}
+
+impl MessageFormat {
+    fn report(&self, event: &Event) {
+        if *self == MessageFormat::Json {
+            println!("{}", event.to_json_line());
+        }
     }
 }
 
-fn ok_or_exit<T, E>(res: Result<T, E>) -> T
-    where E: std::error::Error
+fn ok_or_exit<T, E, F>(res: Result<T, E>, format: MessageFormat, exit_code: F) -> T
+    where E: std::error::Error,
+          F: FnOnce(&E) -> i32
 {
     match res {
         Ok(res) => res,
         Err(err) => {
-            write!(stderr(), "{}\n", WithCauses(&err)).ok();
-            exit(3);
+            match format {
+                MessageFormat::Human => {
+                    write!(stderr(), "{}\n", WithCauses(&err)).ok();
+                }
+                MessageFormat::Json => format.report(&Event::error(&err)),
+            }
+            exit(exit_code(&err));
         }
     }
 }
 
+fn configuration_error(_: &Error) -> i32 {
+    Failure::Configuration.exit_code()
+}
+
+fn reject_dry_run<'a>(matches: &ArgMatches<'a>,
+                      args: &ArgMatches<'a>,
+                      command: &'static str,
+                      format: MessageFormat) {
+    if is_present(matches, args, "dry-run") {
+        let res: Result<(), _> = Err(Error::DryRunNotSupported(command));
+        ok_or_exit(res, format, |_| Failure::Usage.exit_code());
+    }
+}
+
 fn context_from<'a>(args: &'a ArgMatches<'a>) -> Result<(PathBuf, anders::Context), Error> {
     let (context_path, context_dir) = {
         let path = PathBuf::from(args.value_of("context").expect("context to be mandatory"));
@@ -73,14 +124,15 @@ fn context_from<'a>(args: &'a ArgMatches<'a>) -> Result<(PathBuf, anders::Contex
         }
     };
     let mut file = try!(File::open(&context_path).context(context_path.as_path()));
-    anders::Context::deserialize(&mut file)
-        .map(|ctx| (context_dir, ctx))
-        .map_err(|err| Error::ContextSchema(context_path.to_owned(), err))
+    let ctx = try!(anders::Context::deserialize(&mut file)
+        .map_err(|err| Error::ContextSchema(context_path.to_owned(), err)));
+    try!(ctx.verify().map_err(|err| Error::ContextInvalid(context_path.to_owned(), err)));
+    Ok((context_dir, ctx))
 }
 
 fn build_tasks() -> HashMap<String, anders::Task> {
     let mut map = HashMap::new();
-    for task_name in &[COMPILE_COMMAND, PACKAGE_COMMAND, LAUNCH_COMMAND] {
+    for task_name in &[COMPILE_COMMAND, PACKAGE_COMMAND, LAUNCH_COMMAND, VERIFY_COMMAND] {
         map.insert(String::from(*task_name),
                    anders::Task {
                        before: Some(format!("echo before {}", task_name)),
@@ -96,9 +148,51 @@ fn to_context<'a>(args: &ArgMatches<'a>) -> anders::Context {
         package: args.value_of("package").expect("package to be mandatory").to_owned(),
         target: args.value_of("target").expect("target to be mandatory").to_owned(),
         tasks: build_tasks(),
+        ..Default::default()
     }
 }
 
+fn device_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![Arg::with_name("device")
+             .long("device")
+             .short("s")
+             .takes_value(true)
+             .value_name("serial")
+             .conflicts_with_all(&["usb", "emulator"])
+             .help("use the device with the given serial, as listed by 'adb devices'. Defaults \
+                    to $ANDROID_SERIAL, then to 'default_device' in anders.json"),
+         Arg::with_name("usb")
+             .long("usb")
+             .short("d")
+             .conflicts_with("emulator")
+             .help("use the only device connected by USB"),
+         Arg::with_name("emulator")
+             .long("emulator")
+             .short("e")
+             .help("use the only running emulator")]
+}
+
+fn log_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![Arg::with_name("priority")
+             .long("priority")
+             .short("p")
+             .takes_value(true)
+             .validator(|p| {
+                 Priority::parse(&p)
+                     .map(|_| ())
+                     .ok_or_else(|| format!("'{}' is not a log priority, like 'warn'", p))
+             })
+             .help("show only log lines of at least this priority, overriding \
+                    'logcat.priority' in anders.json"),
+         Arg::with_name("tag")
+             .long("tag")
+             .short("t")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .help("show only log lines with this tag, overriding 'logcat.tags' in anders.json")]
+}
+
 fn new_app<'a, 'b>() -> App<'a, 'b> {
     fn context<'a, 'b>() -> Arg<'a, 'b> {
         Arg::with_name("context")
@@ -114,6 +208,56 @@ fn new_app<'a, 'b>() -> App<'a, 'b> {
         .version("1.0")
         .author("Sebastian Thiel")
         .about("Comfortable android development from your command-line")
+        .arg(Arg::with_name("no-tool-cache")
+            .long("no-tool-cache")
+            .global(true)
+            .help("search PATH and ANDROID_HOME for tools on every lookup instead of using the \
+                   tools found by previous invocations"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .global(true)
+            .help("show the output of programs only if they fail"))
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .global(true)
+            .help("print the programs and tasks compile, package, verify, launch or logcat would \
+                   run instead of running them. Other commands reject it"))
+        .arg(Arg::with_name("plan-format")
+            .long("plan-format")
+            .global(true)
+            .takes_value(true)
+            .possible_values(&["shell", "json"])
+            .help("the format in which --dry-run prints the programs it would run, defaults to \
+                   shell"))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .global(true)
+            .takes_value(true)
+            .value_name("seconds")
+            .validator(is_seconds)
+            .help("terminate each program which runs longer than this, overriding \
+                   'timeouts.command' in anders.json"))
+        .arg(Arg::with_name("stage-timeout")
+            .long("stage-timeout")
+            .global(true)
+            .takes_value(true)
+            .value_name("seconds")
+            .validator(is_seconds)
+            .help("terminate the programs of the command once it runs longer than this, \
+                   overriding 'timeouts.stages' in anders.json"))
+        .arg(Arg::with_name("propagate-exit-code")
+            .long("propagate-exit-code")
+            .global(true)
+            .help("if a program fails, exit with its exit code instead of the one of the failure \
+                   category"))
+        .arg(Arg::with_name("message-format")
+            .long("message-format")
+            .global(true)
+            .takes_value(true)
+            .possible_values(&["human", "json"])
+            .help("print newline-delimited json events to stdout instead of text, which implies \
+                   --quiet. Defaults to human"))
         .subcommand(SubCommand::with_name("new")
             .display_order(0)
             .about("create scaffolding for a new hello-world android app")
@@ -145,39 +289,361 @@ fn new_app<'a, 'b>() -> App<'a, 'b> {
             .about("package previously compiled artifacts into a package signed with the Android \
                     Debug Key")
             .version("0.1")
-            .arg(context()))
-        .subcommand(SubCommand::with_name("launch")
+            .arg(context())
+            .arg(Arg::with_name("release")
+                .long("release")
+                .help("sign with the key configured in the 'signing' section of the context \
+                       instead of the Android Debug Key")))
+        .subcommand(SubCommand::with_name("verify")
             .display_order(3)
-            .about("send a previously created signed package to the currently running simulator. \
-                    The latter will be brought up if needed.")
+            .about("verify the signatures of a previously created package using apksigner")
             .version("0.1")
             .arg(context()))
+        .subcommand(SubCommand::with_name("launch")
+            .display_order(4)
+            .about("send a previously created signed package to a connected device or emulator, \
+                    and start its launcher activity")
+            .version("0.1")
+            .arg(context())
+            .args(&device_args())
+            .args(&log_filter_args())
+            .arg(Arg::with_name("logcat")
+                .long("logcat")
+                .help("show the log of the application once it started, until interrupted"))
+            .arg(Arg::with_name("native-adb")
+                .long("native-adb")
+                .help("talk to the adb server directly instead of running the adb program"))
+            .arg(Arg::with_name("clean-install")
+                .long("clean-install")
+                .help("uninstall the application first, which removes its data"))
+            .arg(Arg::with_name("wait")
+                .long("wait")
+                .short("W")
+                .conflicts_with("debug")
+                .help("wait until the activity is displayed"))
+            .arg(Arg::with_name("extra")
+                .long("extra")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("key=value")
+                .validator(is_extra)
+                .help("pass a string extra with the intent starting the activity"))
+            .arg(Arg::with_name("debug")
+                .long("debug")
+                .short("D")
+                .help("have the application wait for a debugger, which can attach to \
+                       localhost:8700 or the port given by --debug-port"))
+            .arg(Arg::with_name("debug-port")
+                .long("debug-port")
+                .takes_value(true)
+                .value_name("port")
+                .requires("debug")
+                .validator(|port| {
+                    port.parse::<u16>().map(|_| ()).map_err(|_| format!("'{}' is not a port", port))
+                })
+                .help("the local port the debugger can attach to")))
+        .subcommand(SubCommand::with_name("logcat")
+            .display_order(5)
+            .about("show the log of the application, following it across restarts, until \
+                    interrupted")
+            .version("0.1")
+            .arg(context())
+            .args(&device_args())
+            .args(&log_filter_args()))
+        .subcommand(SubCommand::with_name(DOCTOR_COMMAND)
+            .display_order(6)
+            .about("check that all tools needed for building are installed, and explain how to \
+                    install those that are missing")
+            .version("0.1")
+            .arg(context())
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("print the diagnosis as json")))
+}
+
+/// Returns true if the global flag `name` was given before or after the subcommand.
+fn is_present<'a>(matches: &ArgMatches<'a>, subcommand_args: &ArgMatches<'a>, name: &str) -> bool {
+    matches.is_present(name) || subcommand_args.is_present(name)
+}
+
+/// Returns the value of the global option `name`, given before or after the subcommand.
+fn value_of<'a>(matches: &'a ArgMatches<'a>,
+                subcommand_args: &'a ArgMatches<'a>,
+                name: &str)
+                -> Option<&'a str> {
+    matches.value_of(name).or_else(|| subcommand_args.value_of(name))
+}
+
+fn is_extra(value: String) -> Result<(), String> {
+    if value.contains('=') {
+        Ok(())
+    } else {
+        Err(format!("'{}' is not of the form key=value", value))
+    }
+}
+
+fn device_selector<'a>(args: &ArgMatches<'a>, ctx: &anders::Context) -> DeviceSelector {
+    if let Some(serial) = args.value_of("device") {
+        DeviceSelector::Serial(serial.to_owned())
+    } else if args.is_present("usb") {
+        DeviceSelector::Usb
+    } else if args.is_present("emulator") {
+        DeviceSelector::Emulator
+    } else {
+        DeviceSelector::configured(ctx)
+    }
+}
+
+fn log_filter<'a>(args: &ArgMatches<'a>, ctx: &anders::Context) -> LogFilter {
+    let mut filter = LogFilter::from_context(ctx);
+    if let Some(priority) = args.value_of("priority") {
+        filter.priority = Priority::parse(priority).expect("validated by clap");
+    }
+    if let Some(tags) = args.values_of("tag") {
+        filter.tags = tags.map(str::to_owned).collect();
+    }
+    filter
+}
+
+fn print_log_line(format: MessageFormat, line: &LogLine) {
+    match format {
+        MessageFormat::Json => format.report(&Event::Log(line.clone())),
+        MessageFormat::Human if stdout_is_terminal() => {
+            println!("{}{}\x1b[0m", line.priority.color(), line)
+        }
+        MessageFormat::Human => println!("{}", line),
+    }
+}
+
+fn launch_options<'a>(args: &ArgMatches<'a>, ctx: &anders::Context) -> LaunchOptions {
+    let device = device_selector(args, ctx);
+    let extras = args.values_of("extra")
+        .map(|extras| {
+            extras.map(|extra| {
+                    let mut parts = extra.splitn(2, '=');
+                    let key = parts.next().expect("validated by clap").to_owned();
+                    (key, parts.next().expect("validated by clap").to_owned())
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let debug_port = if args.is_present("debug") {
+        Some(args.value_of("debug-port")
+            .map(|port| port.parse().expect("validated by clap"))
+            .unwrap_or(DEFAULT_DEBUG_PORT))
+    } else {
+        None
+    };
+    LaunchOptions {
+        device: device,
+        clean_install: args.is_present("clean-install"),
+        wait: args.is_present("wait"),
+        extras: extras,
+        debug_port: debug_port,
+        native_adb: args.is_present("native-adb"),
+    }
+}
+
+fn is_seconds(value: String) -> Result<(), String> {
+    value.parse::<u64>().map(|_| ()).map_err(|_| format!("'{}' is not a number of seconds", value))
+}
+
+fn seconds_of<'a>(matches: &'a ArgMatches<'a>,
+                  subcommand_args: &'a ArgMatches<'a>,
+                  name: &str)
+                  -> Option<Duration> {
+    value_of(matches, subcommand_args, name)
+        .map(|secs| Duration::from_secs(secs.parse().expect("validated by clap")))
 }
 
 fn handle(matches: ArgMatches) {
+    let no_tool_cache = match matches.subcommand() {
+        (_, Some(args)) => is_present(&matches, args, "no-tool-cache"),
+        _ => false,
+    };
+    anders::set_tool_cache_mode(if no_tool_cache {
+        anders::ToolCacheMode::Disabled
+    } else {
+        anders::ToolCacheMode::OnDisk
+    });
+    let format = match matches.subcommand() {
+        (_, Some(args)) if value_of(&matches, args, "message-format") == Some("json") => {
+            MessageFormat::Json
+        }
+        _ => MessageFormat::Human,
+    };
     match matches.subcommand() {
         ("new", Some(args)) => {
-            ok_or_exit(generate_application_scaffolding(&to_context(args)));
+            reject_dry_run(&matches, args, "new", format);
+            format.report(&Event::StageStarted { stage: "new".to_owned() });
+            let started = Instant::now();
+            let res = generate_application_scaffolding(&to_context(args));
+            format.report(&Event::StageFinished {
+                stage: "new".to_owned(),
+                success: res.is_ok(),
+                duration: started.elapsed(),
+            });
+            ok_or_exit(res, format, |err| err.failure().exit_code());
+        }
+        (DOCTOR_COMMAND, Some(args)) => {
+            reject_dry_run(&matches, args, DOCTOR_COMMAND, format);
+            let ctx = match context_from(args) {
+                Ok((_, ctx)) => Some(ctx),
+                Err(Error::ContextReadingIo(..)) => None,
+                Err(err) => ok_or_exit(Err(err), format, configuration_error),
+            };
+            let timeout = seconds_of(&matches, args, "timeout")
+                .or_else(|| {
+                    ctx.as_ref().and_then(|ctx| ctx.timeouts.as_ref()).and_then(|t| {
+                        t.command_timeout()
+                    })
+                })
+                .unwrap_or(Duration::from_secs(VERSION_TIMEOUT_SECS));
+            let spawner = SpawningRunner {
+                verbosity: Verbosity::Quiet,
+                timeout: Some(timeout),
+                ..SpawningRunner::default()
+            };
+            let runner = ReportingRunner {
+                runner: &spawner,
+                report: |event: &Event| format.report(event),
+            };
+            let diagnosis = diagnose(ctx.as_ref(), &runner);
+            if format == MessageFormat::Json {
+                format.report(&Event::Diagnosis(diagnosis.clone()));
+            } else if args.is_present("json") {
+                println!("{}", diagnosis.to_pretty_json());
+            } else {
+                print!("{}", diagnosis);
+            }
+            if !diagnosis.is_healthy() {
+                exit(Failure::MissingTool.exit_code());
+            }
         }
         (cmd @ "compile", Some(args)) |
         (cmd @ "package", Some(args)) |
-        (cmd @ "launch", Some(args)) => {
-            let (project_root, ctx) = ok_or_exit(context_from(args));
-            match cmd {
-                "compile" => ok_or_exit(compile_application(&project_root, &ctx)),
-                "package" => ok_or_exit(package_application(&project_root, &ctx)),
-                "launch" => ok_or_exit(launch_application(&project_root, &ctx)),
+        (cmd @ "launch", Some(args)) |
+        (cmd @ "verify", Some(args)) |
+        (cmd @ "logcat", Some(args)) => {
+            let (project_root, ctx) = ok_or_exit(context_from(args), format, configuration_error);
+            let dry_run = is_present(&matches, args, "dry-run");
+            let quiet = is_present(&matches, args, "quiet") || format == MessageFormat::Json;
+            let timeouts = ctx.timeouts.as_ref();
+            let timeout = seconds_of(&matches, args, "timeout")
+                .or_else(|| timeouts.and_then(|t| t.command_timeout()));
+            let stage_timeout = seconds_of(&matches, args, "stage-timeout")
+                .or_else(|| timeouts.and_then(|t| t.stage_timeout(cmd)));
+            let spawner = SpawningRunner {
+                verbosity: if quiet {
+                    Verbosity::Quiet
+                } else {
+                    Verbosity::Verbose
+                },
+                timeout: timeout,
+                deadline: stage_timeout.map(|timeout| Instant::now() + timeout),
+                stage_timeout: stage_timeout,
+            };
+            let recorder = RecordingRunner::new();
+            let runner = ReportingRunner {
+                runner: if dry_run { &recorder } else { &spawner },
+                report: |event: &Event| format.report(event),
+            };
+
+            format.report(&Event::StageStarted { stage: cmd.to_owned() });
+            let started = Instant::now();
+            let res = match cmd {
+                "compile" => compile_application(&project_root, &ctx, &runner),
+                "package" => {
+                    let profile = if args.is_present("release") {
+                        Profile::Release
+                    } else {
+                        Profile::Debug
+                    };
+                    package_application(&project_root, &ctx, profile, &runner)
+                }
+                "launch" => {
+                    let options = launch_options(args, &ctx);
+                    let res = launch_application(&project_root, &ctx, &options, &runner);
+                    match options.debug_port {
+                        Some(port) if res.is_ok() && !dry_run &&
+                                      format == MessageFormat::Human => {
+                            println!("Attach a debugger with 'jdb -attach localhost:{}'", port);
+                        }
+                        _ => {}
+                    }
+                    if res.is_ok() && args.is_present("logcat") {
+                        stream_log(&project_root,
+                                   &ctx,
+                                   &options.device,
+                                   &log_filter(args, &ctx),
+                                   &runner,
+                                   |line| print_log_line(format, line))
+                    } else {
+                        res
+                    }
+                }
+                "logcat" => {
+                    stream_log(&project_root,
+                               &ctx,
+                               &device_selector(args, &ctx),
+                               &log_filter(args, &ctx),
+                               &runner,
+                               |line| print_log_line(format, line))
+                }
+                "verify" => verify_application(&project_root, &ctx, &runner),
                 _ => unreachable!(),
+            };
+            if let Err(BatchExecutionError::Diagnostics(_, ref diagnostics, _)) = res {
+                for diagnostic in diagnostics {
+                    format.report(&Event::Diagnostic(diagnostic.clone()));
+                }
+            }
+            format.report(&Event::StageFinished {
+                stage: cmd.to_owned(),
+                success: res.is_ok(),
+                duration: started.elapsed(),
+            });
+            let propagate_exit_code = is_present(&matches, args, "propagate-exit-code");
+            ok_or_exit(res, format, |err| {
+                err.program_exit_code()
+                    .and_then(|code| if propagate_exit_code { Some(code) } else { None })
+                    .unwrap_or_else(|| err.failure(cmd).exit_code())
+            });
+
+            if cmd == PACKAGE_COMMAND && !dry_run {
+                let path = apk_path(&ctx);
+                if let Ok(fingerprint) = fingerprint_file(&project_root.join(&path)) {
+                    format.report(&Event::Artifact {
+                        path: path,
+                        fingerprint: fingerprint,
+                    });
+                }
+            }
+            if dry_run {
+                let plan = recorder.into_plan();
+                match (format, value_of(&matches, args, "plan-format")) {
+                    (MessageFormat::Json, _) => format.report(&Event::Plan(plan)),
+                    (MessageFormat::Human, Some("json")) => println!("{}", plan.to_json()),
+                    (MessageFormat::Human, _) => print!("{}", plan.to_shell_script()),
+                }
             }
         }
         _ => {
             println!("{}", matches.usage());
-            exit(4);
+            exit(Failure::Usage.exit_code());
         }
     }
 }
 
 fn main() {
-    let matches = new_app().get_matches();
+    anders::handle_interrupts();
+    let matches = new_app().get_matches_safe().unwrap_or_else(|err| match err.kind {
+        ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => err.exit(),
+        _ => {
+            write!(stderr(), "{}\n", err.message).ok();
+            exit(Failure::Usage.exit_code());
+        }
+    });
     handle(matches);
 }
@@ -0,0 +1,312 @@
+use std::path::{Path, PathBuf};
+use std::fs::{File, create_dir_all, remove_dir_all, read_dir};
+use std::io::{Read, Write};
+use regex::{Regex, Captures};
+use quick_error::ResultExt;
+use super::{Context, BatchExecutionError, LibraryPath, library_archives, fingerprint_file,
+            path_argument, CommandRunner};
+
+pub const MANIFEST_FILENAME: &'static str = "AndroidManifest.xml";
+pub const AAR_DIR: &'static str = ".anders/aar";
+pub const MERGED_MANIFEST_PATH: &'static str = ".anders/AndroidManifest.xml";
+const FINGERPRINT_FILENAME: &'static str = ".fingerprint";
+
+/// An android library archive, unpacked into its own directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Aar {
+    pub archive: PathBuf,
+    pub dir: PathBuf,
+    pub package: Option<String>,
+}
+
+impl Aar {
+    /// Returns the jar with the compiled classes of the library, as well as all jars it bundles.
+    pub fn jars(&self) -> Result<Vec<PathBuf>, BatchExecutionError> {
+        let mut jars = Vec::new();
+        let classes = self.dir.join("classes.jar");
+        if classes.is_file() {
+            jars.push(classes);
+        }
+        let libs = self.dir.join("libs");
+        if libs.is_dir() {
+            let mut bundled = Vec::new();
+            for entry in try!(read_dir(&libs).context(LibraryPath(&libs))) {
+                let path = try!(entry.context(LibraryPath(&libs))).path();
+                if path.extension().map(|ext| ext == "jar").unwrap_or(false) {
+                    bundled.push(path);
+                }
+            }
+            bundled.sort();
+            jars.extend(bundled);
+        }
+        Ok(jars)
+    }
+
+    pub fn resource_dir(&self) -> Option<PathBuf> {
+        let res = self.dir.join("res");
+        if res.is_dir() { Some(res) } else { None }
+    }
+
+    pub fn manifest(&self) -> PathBuf {
+        self.dir.join(MANIFEST_FILENAME)
+    }
+}
+
+fn read_utf8_file(path: &Path) -> Result<String, BatchExecutionError> {
+    let mut buf = String::new();
+    try!(File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut buf))
+        .context(LibraryPath(path)));
+    Ok(buf)
+}
+
+fn write_utf8_file(contents: &str, path: &Path) -> Result<(), BatchExecutionError> {
+    if let Some(dir) = path.parent() {
+        try!(create_dir_all(dir).context(LibraryPath(dir)));
+    }
+    try!(File::create(path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .context(LibraryPath(path)));
+    Ok(())
+}
+
+pub fn manifest_package(manifest: &str) -> Option<String> {
+    Regex::new(r#"<manifest[^>]*\spackage\s*=\s*"([^"]+)""#)
+        .expect("valid package regex")
+        .captures(manifest)
+        .and_then(|c| c.at(1).map(str::to_owned))
+}
+
+/// Unpacks all archives with the `aar` extension in the library directories and dependencies
+/// into their own directory below `AAR_DIR`, unless they were unpacked before and didn't change.
+pub fn unpack_aars(at: &Path,
+                   ctx: &Context,
+                   dependencies: &[PathBuf],
+                   runner: &CommandRunner)
+                   -> Result<Vec<Aar>, BatchExecutionError> {
+    let archives = try!(library_archives(at, ctx, dependencies, "aar"));
+    if archives.is_empty() {
+        return Ok(Vec::new());
+    }
+    let jar_path = try!(runner.find_program("jar"));
+    let mut aars = Vec::with_capacity(archives.len());
+    for archive in archives {
+        let dir = at.join(AAR_DIR)
+            .join(archive.file_stem().expect("archive to have a file name"));
+        let fingerprint = try!(fingerprint_file(&archive).context(LibraryPath(&archive)));
+        let fingerprint_path = dir.join(FINGERPRINT_FILENAME);
+        let is_unpacked = read_utf8_file(&fingerprint_path)
+            .map(|previous| previous == fingerprint)
+            .unwrap_or(false);
+        if !is_unpacked {
+            let dry_run = !runner.produces_outputs();
+            if dir.is_dir() && !dry_run {
+                try!(remove_dir_all(&dir).context(LibraryPath(&dir)));
+            }
+            if !dry_run {
+                try!(create_dir_all(&dir).context(LibraryPath(&dir)));
+            }
+            let archive_path = at.join(&archive);
+            try!(runner.run(&dir, &jar_path, &["xf", &archive_path.to_string_lossy()]));
+            if !dry_run {
+                try!(write_utf8_file(&fingerprint, &fingerprint_path));
+            }
+        }
+        let mut aar = Aar {
+            archive: archive,
+            dir: dir,
+            package: None,
+        };
+        if aar.manifest().is_file() {
+            aar.package = manifest_package(&try!(read_utf8_file(&aar.manifest())));
+        }
+        aars.push(aar);
+    }
+    Ok(aars)
+}
+
+/// Merges permissions, features and application components of the `library` manifest into the
+/// `app` manifest. Relative component names are qualified with the library's package.
+pub fn merge_manifest(app: &str, library: &str) -> String {
+    // A self-closing application element has to be opened up to take the library's components.
+    let mut merged = Regex::new(r"(?s)<application\b([^>]*?)\s*/>")
+        .expect("valid empty application regex")
+        .replace(app, |c: &Captures| {
+            format!("<application{}></application>", c.at(1).expect("single capture"))
+        });
+    let mut top_level = String::new();
+    let re_top_level = Regex::new(r"(?s)<uses-(?:permission|feature)\b[^>]*?/>")
+        .expect("valid top-level regex");
+    for element in re_top_level.find_iter(library).map(|(s, e)| &library[s..e]) {
+        if !app.contains(element) {
+            top_level.push_str(element);
+            top_level.push('\n');
+        }
+    }
+    if let Some(start) = merged.find("<application") {
+        merged.insert_str(start, &top_level);
+    }
+
+    let components = Regex::new(r"(?s)<application\b[^>]*?>(.*)</application>")
+        .expect("valid application regex")
+        .captures(library)
+        .and_then(|c| c.at(1).map(str::to_owned));
+    if let (Some(components), Some(package)) = (components, manifest_package(library)) {
+        let components = Regex::new(r#"android:name\s*=\s*"\.([^"]+)""#)
+            .expect("valid name regex")
+            .replace_all(&components, |c: &Captures| {
+                format!(r#"android:name="{}.{}""#, package, c.at(1).expect("single capture"))
+            });
+        if let Some(end) = merged.rfind("</application>") {
+            merged.insert_str(end, &components);
+        }
+    }
+    merged
+}
+
+/// Returns the path of the manifest to build with, which is the application's own manifest unless
+/// libraries need to be merged into it.
+pub fn effective_manifest(at: &Path,
+                          aars: &[Aar],
+                          runner: &CommandRunner)
+                          -> Result<String, BatchExecutionError> {
+    if aars.is_empty() {
+        return Ok(MANIFEST_FILENAME.to_owned());
+    }
+    if !runner.produces_outputs() {
+        return Ok(MERGED_MANIFEST_PATH.to_owned());
+    }
+    let mut merged = try!(read_utf8_file(&at.join(MANIFEST_FILENAME)));
+    for aar in aars.iter().filter(|aar| aar.manifest().is_file()) {
+        merged = merge_manifest(&merged, &try!(read_utf8_file(&aar.manifest())));
+    }
+    try!(write_utf8_file(&merged, &at.join(MERGED_MANIFEST_PATH)));
+    Ok(MERGED_MANIFEST_PATH.to_owned())
+}
+
+/// Returns `aapt` arguments which add the resources of all libraries as overlays to those of the
+/// application, and have `R` classes generated for each library package.
+pub fn aapt_library_args(at: &Path, aars: &[Aar]) -> Vec<String> {
+    let mut args = Vec::new();
+    for res in aars.iter().filter_map(Aar::resource_dir) {
+        args.push("-S".to_owned());
+        args.push(path_argument(at, &res));
+    }
+    if !args.is_empty() {
+        args.push("--auto-add-overlay".to_owned());
+    }
+    let packages: Vec<_> = aars.iter().filter_map(|aar| aar.package.as_ref()).cloned().collect();
+    if !packages.is_empty() {
+        args.push("--extra-packages".to_owned());
+        args.push(packages.join(":"));
+    }
+    args
+}
+
+
+#[test]
+fn test_merge_manifest() {
+    let app = r#"<manifest package="com.app">
+<uses-permission android:name="android.permission.INTERNET"/>
+<application android:label="app">
+<activity android:name=".Main"/>
+</application>
+</manifest>"#;
+    let library = r#"<manifest xmlns:android="http://schemas.android.com/apk/res/android"
+    package="com.lib">
+<uses-permission android:name="android.permission.INTERNET"/>
+<uses-permission android:name="android.permission.CAMERA"/>
+<application><service android:name=".Sync"/></application>
+</manifest>"#;
+    assert_eq!(manifest_package(library), Some("com.lib".to_owned()));
+    assert_eq!(merge_manifest(app, library),
+               r#"<manifest package="com.app">
+<uses-permission android:name="android.permission.INTERNET"/>
+<uses-permission android:name="android.permission.CAMERA"/>
+<application android:label="app">
+<activity android:name=".Main"/>
+<service android:name="com.lib.Sync"/></application>
+</manifest>"#);
+
+    let app = r#"<manifest package="com.app">
+<application android:label="app" />
+</manifest>"#;
+    assert_eq!(merge_manifest(app, library),
+               r#"<manifest package="com.app">
+<uses-permission android:name="android.permission.INTERNET"/>
+<uses-permission android:name="android.permission.CAMERA"/>
+<application android:label="app"><service android:name="com.lib.Sync"/></application>
+</manifest>"#);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unpack_aars_only_when_changed() {
+    use std::fs::remove_dir_all;
+    use super::{RecordingRunner, Sdk, unique_temp_dir};
+
+    let dir = unique_temp_dir("unpack-aars");
+    let (at, sdk_root) = (dir.join("app"), dir.join("sdk"));
+    let archive = at.join("lib/b.aar");
+    let unpacked = at.join(AAR_DIR).join("b");
+    let ctx = Context::default();
+    create_dir_all(sdk_root.join("build-tools/27.0.3")).unwrap();
+    let unpack = || {
+        let runner = RecordingRunner::assuming_sdk(Sdk::at(sdk_root.clone(), &ctx).unwrap());
+        let aars = unpack_aars(&at, &ctx, &[], &runner).unwrap();
+        (aars, runner.into_plan().invocations)
+    };
+
+    write_utf8_file("first", &archive).unwrap();
+    let (aars, invocations) = unpack();
+    assert_eq!(aars.len(), 1);
+    assert_eq!(aars[0].dir, unpacked);
+    assert_eq!(invocations.len(), 1);
+    assert!(invocations[0].executable.ends_with("jar"));
+    assert_eq!(invocations[0].args, vec!["xf".to_owned(), archive.display().to_string()]);
+
+    let fingerprint = fingerprint_file(&archive).unwrap();
+    write_utf8_file(&fingerprint, &unpacked.join(FINGERPRINT_FILENAME)).unwrap();
+    write_utf8_file(r#"<manifest package="com.lib"/>"#, &unpacked.join(MANIFEST_FILENAME))
+        .unwrap();
+    let (unchanged, unchanged_invocations) = unpack();
+
+    write_utf8_file("second", &archive).unwrap();
+    let (_, changed_invocations) = unpack();
+    remove_dir_all(&dir).unwrap();
+    assert_eq!(unchanged_invocations.len(), 0);
+    assert_eq!(unchanged[0].package, Some("com.lib".to_owned()));
+    assert_eq!(changed_invocations.len(), 1);
+}
+
+#[test]
+fn test_aapt_library_args() {
+    use std::fs::remove_dir_all;
+    use super::unique_temp_dir;
+
+    let at = unique_temp_dir("aapt-library-args");
+    let aar = |name: &str, package: Option<&str>| {
+        Aar {
+            archive: at.join(format!("lib/{}.aar", name)),
+            dir: at.join(AAR_DIR).join(name),
+            package: package.map(str::to_owned),
+        }
+    };
+    let aars = [aar("a", Some("com.a")), aar("b", Some("com.b")), aar("c", None)];
+    for name in &["a", "c"] {
+        create_dir_all(at.join(AAR_DIR).join(name).join("res")).unwrap();
+    }
+    let args = aapt_library_args(&at, &aars);
+    let without_resources = aapt_library_args(&at, &aars[1..2]);
+    remove_dir_all(&at).unwrap();
+    assert_eq!(args,
+               vec!["-S",
+                    ".anders/aar/a/res",
+                    "-S",
+                    ".anders/aar/c/res",
+                    "--auto-add-overlay",
+                    "--extra-packages",
+                    "com.a:com.b"]);
+    assert_eq!(without_resources, vec!["--extra-packages", "com.b"]);
+    assert!(aapt_library_args(&at, &[]).is_empty());
+}
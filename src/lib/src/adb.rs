@@ -0,0 +1,339 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::{Device, DeviceSelector, parse_adb_devices};
+
+/// The port the adb server listens on unless `ANDROID_ADB_SERVER_PORT` says otherwise.
+pub const DEFAULT_ADB_SERVER_PORT: u16 = 5037;
+pub const ADB_SERVER_PORT_ENV_VAR: &'static str = "ANDROID_ADB_SERVER_PORT";
+
+/// The largest amount of file contents the sync protocol allows in one `DATA` packet.
+const SYNC_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The mode of pushed files, a regular file readable by everyone.
+const PUSH_FILE_MODE: u32 = 0o100644;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum AdbError {
+        Connect(address: String, err: io::Error) {
+            description("The adb server could not be reached")
+            display("Failed to connect to the adb server at {}, start it with 'adb start-server'",
+                    address)
+            cause(err)
+        }
+        Io(err: io::Error) {
+            description("Talking to the adb server failed")
+            from()
+            cause(err)
+        }
+        ReadFile(path: PathBuf, err: io::Error) {
+            description("A file to push could not be read")
+            display("Failed to read '{}' to push it to the device", path.display())
+            cause(err)
+        }
+        Failed(request: String, message: String) {
+            description("The adb server refused a request")
+            display("The adb server failed to handle '{}': {}", request, message)
+        }
+        Protocol(message: String) {
+            description("The adb server answered unexpectedly")
+            display("Unexpected answer from the adb server: {}", message)
+        }
+    }
+}
+
+/// Talks to the adb server with its smart-socket protocol, which does what the `adb` program
+/// does without spawning it. Each request uses a connection of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdbClient {
+    pub host: String,
+    pub port: u16,
+    /// How long to wait for the server to accept or send data before failing.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for AdbClient {
+    fn default() -> Self {
+        AdbClient::local()
+    }
+}
+
+impl AdbClient {
+    /// Returns a client for the server on this machine, using the same port as `adb` does.
+    pub fn local() -> AdbClient {
+        AdbClient {
+            host: "127.0.0.1".to_owned(),
+            port: env::var(ADB_SERVER_PORT_ENV_VAR)
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(DEFAULT_ADB_SERVER_PORT),
+            timeout: None,
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, AdbError> {
+        let stream = try!(TcpStream::connect((&*self.host, self.port))
+            .map_err(|err| AdbError::Connect(format!("{}:{}", self.host, self.port), err)));
+        // a zero timeout is invalid, so an expired one has to be slightly longer
+        let timeout = self.timeout.map(|timeout| timeout.max(Duration::from_millis(1)));
+        try!(stream.set_read_timeout(timeout));
+        try!(stream.set_write_timeout(timeout));
+        Ok(stream)
+    }
+
+    /// Returns a connection whose requests are forwarded to `device`.
+    fn connect_to(&self, device: &DeviceSelector) -> Result<TcpStream, AdbError> {
+        let mut stream = try!(self.connect());
+        let transport = match *device {
+            DeviceSelector::Any => "host:transport-any".to_owned(),
+            DeviceSelector::Usb => "host:transport-usb".to_owned(),
+            DeviceSelector::Emulator => "host:transport-local".to_owned(),
+            DeviceSelector::Serial(ref serial) => format!("host:transport:{}", serial),
+        };
+        try!(request(&mut stream, &transport));
+        Ok(stream)
+    }
+
+    /// Returns the devices as `adb devices -l` lists them.
+    pub fn devices(&self) -> Result<Vec<Device>, AdbError> {
+        let mut stream = try!(self.connect());
+        try!(request(&mut stream, "host:devices-l"));
+        let list = try!(read_length_prefixed(&mut stream));
+        Ok(parse_adb_devices(&list.lines().map(String::from).collect::<Vec<_>>()))
+    }
+
+    /// Runs `command` with the shell of `device` and returns its output. Its exit code is not
+    /// transmitted by the protocol.
+    pub fn shell(&self, device: &DeviceSelector, command: &str) -> Result<Vec<String>, AdbError> {
+        let mut stream = try!(self.connect_to(device));
+        try!(request(&mut stream, &format!("shell:{}", command)));
+        let mut output = Vec::new();
+        try!(stream.read_to_end(&mut output));
+        Ok(String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| line.trim_end_matches('\r').to_owned())
+            .collect())
+    }
+
+    /// Copies the file at `local` to `remote` on `device`.
+    pub fn push(&self,
+                device: &DeviceSelector,
+                local: &Path,
+                remote: &str)
+                -> Result<(), AdbError> {
+        let mut file = try!(File::open(local)
+            .map_err(|err| AdbError::ReadFile(local.to_owned(), err)));
+        let mut stream = try!(self.connect_to(device));
+        try!(request(&mut stream, "sync:"));
+        let target = format!("{},{}", remote, PUSH_FILE_MODE);
+        try!(write_sync_header(&mut stream, b"SEND", target.len() as u32));
+        try!(stream.write_all(target.as_bytes()));
+        let mut chunk = vec![0; SYNC_CHUNK_SIZE];
+        loop {
+            let len = try!(file.read(&mut chunk)
+                .map_err(|err| AdbError::ReadFile(local.to_owned(), err)));
+            if len == 0 {
+                break;
+            }
+            try!(write_sync_header(&mut stream, b"DATA", len as u32));
+            try!(stream.write_all(&chunk[..len]));
+        }
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() as u32)
+            .unwrap_or(0);
+        try!(write_sync_header(&mut stream, b"DONE", mtime));
+        let (id, len) = try!(read_sync_header(&mut stream));
+        match &id {
+            b"OKAY" => {}
+            b"FAIL" => {
+                let mut message = vec![0; len as usize];
+                try!(stream.read_exact(&mut message));
+                return Err(AdbError::Failed(format!("push {}", remote),
+                                            String::from_utf8_lossy(&message).into_owned()));
+            }
+            _ => {
+                return Err(AdbError::Protocol(format!("expected OKAY or FAIL after pushing, got \
+                                                       '{}'",
+                                                      String::from_utf8_lossy(&id))))
+            }
+        }
+        try!(write_sync_header(&mut stream, b"QUIT", 0));
+        Ok(())
+    }
+
+    /// Forwards connections to `local` on this machine to `remote` on `device`, like
+    /// `tcp:8700` to `jdwp:1234`.
+    pub fn forward(&self,
+                   device: &DeviceSelector,
+                   local: &str,
+                   remote: &str)
+                   -> Result<(), AdbError> {
+        let prefix = match *device {
+            DeviceSelector::Any => "host".to_owned(),
+            DeviceSelector::Usb => "host-usb".to_owned(),
+            DeviceSelector::Emulator => "host-local".to_owned(),
+            DeviceSelector::Serial(ref serial) => format!("host-serial:{}", serial),
+        };
+        let mut stream = try!(self.connect());
+        request(&mut stream, &format!("{}:forward:{};{}", prefix, local, remote))
+    }
+}
+
+/// Sends `request` prefixed with its length as four hex digits, and reads the status.
+fn request(stream: &mut TcpStream, request: &str) -> Result<(), AdbError> {
+    try!(write!(stream, "{:04x}{}", request.len(), request));
+    let mut status = [0; 4];
+    try!(stream.read_exact(&mut status));
+    match &status {
+        b"OKAY" => Ok(()),
+        b"FAIL" => Err(AdbError::Failed(request.to_owned(), try!(read_length_prefixed(stream)))),
+        _ => {
+            Err(AdbError::Protocol(format!("expected OKAY or FAIL, got '{}'",
+                                           String::from_utf8_lossy(&status))))
+        }
+    }
+}
+
+fn read_length_prefixed(stream: &mut TcpStream) -> Result<String, AdbError> {
+    let mut hex_len = [0; 4];
+    try!(stream.read_exact(&mut hex_len));
+    let hex_len = String::from_utf8_lossy(&hex_len).into_owned();
+    let len = try!(usize::from_str_radix(&hex_len, 16)
+        .map_err(|_| AdbError::Protocol(format!("invalid length '{}'", hex_len))));
+    let mut buf = vec![0; len];
+    try!(stream.read_exact(&mut buf));
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// Sync packets start with a four letter id and a little-endian 32 bit number.
+fn write_sync_header(stream: &mut TcpStream, id: &[u8; 4], value: u32) -> Result<(), AdbError> {
+    let value = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+    try!(stream.write_all(id));
+    try!(stream.write_all(&value));
+    Ok(())
+}
+
+fn read_sync_header(stream: &mut TcpStream) -> Result<([u8; 4], u32), AdbError> {
+    let mut header = [0; 8];
+    try!(stream.read_exact(&mut header));
+    let id = [header[0], header[1], header[2], header[3]];
+    let value = header[4] as u32 | (header[5] as u32) << 8 | (header[6] as u32) << 16 |
+                (header[7] as u32) << 24;
+    Ok((id, value))
+}
+
+/// An adb server answering requests the way the test using it needs.
+#[cfg(test)]
+pub struct FakeAdbServer {
+    pub client: AdbClient,
+    /// The requests received so far.
+    pub requests: ::std::sync::Arc<::std::sync::Mutex<Vec<String>>>,
+    /// The contents of each pushed file.
+    pub pushed: ::std::sync::mpsc::Receiver<Vec<u8>>,
+}
+
+/// Starts a server with a single `emulator-5554`, which answers shell commands with `shell`.
+/// Only the serial of the emulator may be used in forwards.
+#[cfg(test)]
+pub fn fake_adb_server<F>(shell: F) -> FakeAdbServer
+    where F: Fn(&str) -> String + Send + 'static
+{
+    use std::net::TcpListener;
+    use std::thread;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::channel;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = AdbClient {
+        host: "127.0.0.1".to_owned(),
+        port: listener.local_addr().unwrap().port(),
+        timeout: Some(Duration::from_secs(10)),
+    };
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    let (pushed_sender, pushed) = channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            loop {
+                let request = read_length_prefixed(&mut stream).unwrap();
+                received.lock().unwrap().push(request.clone());
+                if request == "host:devices-l" {
+                    let list = "emulator-5554          device product:sdk model:SDK\n";
+                    write!(stream, "OKAY{:04x}{}", list.len(), list).unwrap();
+                    break;
+                } else if request.starts_with("host:transport") {
+                    stream.write_all(b"OKAY").unwrap();
+                } else if request.starts_with("host-serial:emulator-5554:forward:") {
+                    stream.write_all(b"OKAY").unwrap();
+                    break;
+                } else if request.starts_with("shell:") {
+                    write!(stream, "OKAY{}\r\n", shell(&request["shell:".len()..])).unwrap();
+                    break;
+                } else if request == "sync:" {
+                    stream.write_all(b"OKAY").unwrap();
+                    let mut contents = Vec::new();
+                    loop {
+                        let (id, len) = read_sync_header(&mut stream).unwrap();
+                        if &id == b"DONE" {
+                            break;
+                        }
+                        let mut buf = vec![0; len as usize];
+                        stream.read_exact(&mut buf).unwrap();
+                        if &id == b"DATA" {
+                            contents.extend(buf);
+                        }
+                    }
+                    write_sync_header(&mut stream, b"OKAY", 0).unwrap();
+                    assert_eq!(&read_sync_header(&mut stream).unwrap().0, b"QUIT");
+                    pushed_sender.send(contents).unwrap();
+                    break;
+                } else {
+                    let message = "unknown service";
+                    write!(stream, "FAIL{:04x}{}", message.len(), message).unwrap();
+                    break;
+                }
+            }
+        }
+    });
+    FakeAdbServer {
+        client: client,
+        requests: requests,
+        pushed: pushed,
+    }
+}
+
+
+#[test]
+fn test_client_against_fake_server() {
+    let server = fake_adb_server(|command| command.to_owned());
+    let client = &server.client;
+
+    let devices = client.devices().unwrap();
+    assert_eq!(devices.len(), 1);
+    let emulator = DeviceSelector::Serial(devices[0].serial.clone());
+    assert_eq!(client.shell(&emulator, "echo hi").unwrap(), vec!["echo hi"]);
+    for &(ref device, transport) in &[(DeviceSelector::Any, "host:transport-any"),
+                                      (DeviceSelector::Usb, "host:transport-usb"),
+                                      (DeviceSelector::Emulator, "host:transport-local")] {
+        assert_eq!(client.shell(device, "true").unwrap(), vec!["true"]);
+        let requests = server.requests.lock().unwrap();
+        assert_eq!(requests[requests.len() - 2], transport);
+    }
+    client.push(&emulator, Path::new("Cargo.toml"), "/data/local/tmp/Cargo.toml").unwrap();
+    let mut expected = Vec::new();
+    File::open("Cargo.toml").unwrap().read_to_end(&mut expected).unwrap();
+    assert_eq!(server.pushed.recv().unwrap(), expected);
+    client.forward(&emulator, "tcp:8700", "jdwp:1").unwrap();
+    assert_eq!(server.requests.lock().unwrap().last().unwrap(),
+               "host-serial:emulator-5554:forward:tcp:8700;jdwp:1");
+    match client.forward(&DeviceSelector::Usb, "tcp:8700", "jdwp:1") {
+        Err(AdbError::Failed(_, message)) => assert_eq!(message, "unknown service"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
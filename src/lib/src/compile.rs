@@ -1,60 +1,197 @@
-use std::path::{PathBuf, Path};
-use super::{ChangeCWD, find_file_in_path, find_android_executable, execute_program_verbosely,
-            Context, BatchExecutionError, android_platform_jar_path, extract_tasks_for,
-            execute_script};
-use glob::glob;
-use quick_error::ResultExt;
+use std::path::{Path, PathBuf};
+use std::fs::remove_file;
+use std::io;
+use walkdir::WalkDir;
+use super::{CommandRunner, Context, BatchExecutionError, extract_tasks_for, execute_script,
+            BuildState, fingerprint_tree, fingerprint_files, changed_entries, with_dependents,
+            library_jars, path_argument, unpack_aars, effective_manifest, aapt_library_args,
+            dependency_artifacts, MANIFEST_FILENAME, Dexer, with_diagnostics, parse_aapt_output,
+            parse_javac_output};
 use std::env::join_paths;
 
 pub const COMMAND_NAME: &'static str = "compile";
 
-pub fn compile_application(at: &Path, ctx: &Context) -> Result<(), BatchExecutionError> {
-    let (aapt_path, android_home_dir) = try!(find_android_executable("aapt"));
-    let javac_path = try!(find_file_in_path("javac"));
-    let android_jar_path = android_platform_jar_path(&android_home_dir, ctx);
+pub fn compile_application(at: &Path,
+                           ctx: &Context,
+                           runner: &CommandRunner)
+                           -> Result<(), BatchExecutionError> {
+    let sdk = try!(runner.sdk(ctx));
+    let aapt_path = try!(sdk.build_tool("aapt"));
+    let javac_path = try!(runner.find_program("javac"));
+    let android_jar_path = sdk.platform_jar_path(ctx);
     let (before, after) = extract_tasks_for(COMMAND_NAME, ctx);
 
-    try!(execute_script(before, at));
-    try!(execute_program_verbosely(at,
-                                   &aapt_path,
-                                   &["package",
-                                     "-vfm",
-                                     "-S",
-                                     "res",
-                                     "-J",
-                                     "src",
-                                     "-M",
-                                     "AndroidManifest.xml",
-                                     "-I",
-                                     &android_jar_path]));
-
-    let classpath = join_paths(&[&android_jar_path, "obj"])
-        .expect("an android jar path with no invalid characters");
-    let source_files: Vec<_> = {
-        let _in_project_dir = try!(ChangeCWD::into(at).context(at));
-        glob("src/**/*.java")
-            .expect("valid glob")
-            .filter_map(Result::ok)
-            .collect()
+    try!(execute_script(before, at, runner));
+    let dependencies = try!(dependency_artifacts(at, ctx, runner));
+    let aars = try!(unpack_aars(at, ctx, &dependencies, runner));
+    let previous = BuildState::load(at);
+    let mut state = BuildState::default();
+    state.resources = try!(fingerprint_tree(at, "res", |_| true));
+    state.resources.extend(try!(fingerprint_files(at,
+                                                  &[Path::new(MANIFEST_FILENAME),
+                                                    Path::new(&android_jar_path)])));
+    state.resources.extend(try!(fingerprint_files(at,
+                                                  &aars.iter()
+                                                      .map(|aar| aar.archive.as_path())
+                                                      .collect::<Vec<_>>())));
+
+    let r_java_path = at.join(format!("src/{}/R.java", ctx.package.replace(".", "/")));
+    let resources_changed = changed_entries(&previous.resources, &state.resources)
+        .map(|changed| !changed.is_empty())
+        .unwrap_or(true);
+    if resources_changed || !r_java_path.is_file() {
+        let manifest = try!(effective_manifest(at, &aars, runner));
+        let library_args = aapt_library_args(at, &aars);
+        let mut args = vec!["package", "-vfm", "-S", "res"];
+        args.extend(library_args.iter().map(String::as_str));
+        args.extend_from_slice(&["-J", "src", "-M", &manifest, "-I", &android_jar_path]);
+        try!(with_diagnostics("aapt", runner.run(at, &aapt_path, &args), parse_aapt_output));
+    }
+
+    let mut jars = try!(library_jars(at, ctx, &dependencies));
+    for aar in &aars {
+        jars.extend(try!(aar.jars()));
+    }
+    state.libraries = try!(fingerprint_files(at,
+                                             &jars.iter()
+                                                 .map(PathBuf::as_path)
+                                                 .collect::<Vec<_>>())
+        .map_err(|(path, err)| BatchExecutionError::Library(path, err)));
+    let classpath = {
+        let mut entries = vec![android_jar_path.to_owned(), String::from("obj")];
+        entries.extend(jars.iter().map(|jar| path_argument(at, jar)));
+        join_paths(&entries).expect("an android jar path with no invalid characters")
+    };
+    state.sources = try!(fingerprint_tree(at, "src", |p| {
+        p.extension().map(|ext| ext == "java").unwrap_or(false)
+    }));
+
+    let dexer = try!(Dexer::for_sdk(&sdk, ctx));
+    try!(ctx.verify_language_level(dexer.max_language_level()));
+    let options = javac_options(at,
+                                ctx,
+                                dexer.max_language_level(),
+                                classpath.to_str().expect("no non-utf8 characters in jar path"));
+    state.javac_options = options.clone();
+
+    let classpath_changed = previous.resources.get(&android_jar_path) !=
+                            state.resources.get(&android_jar_path) ||
+                            previous.libraries != state.libraries ||
+                            previous.javac_options != state.javac_options;
+    let source_files = match changed_entries(&previous.sources, &state.sources) {
+        Some(ref changed) if changed.is_empty() && !classpath_changed => {
+            if runner.produces_outputs() {
+                try!(state.save(at));
+            }
+            return execute_script(after, at, runner);
+        }
+        Some(changed) if !classpath_changed => {
+            let unchanged: Vec<_> = state.sources
+                .keys()
+                .filter(|k| !changed.contains(*k))
+                .collect();
+            try!(with_dependents(at, changed, &unchanged))
+        }
+        Some(_) | None => {
+            if runner.produces_outputs() {
+                try!(remove_class_files(at));
+            }
+            let mut all: Vec<_> = state.sources.keys().cloned().collect();
+            all.sort();
+            all
+        }
     };
 
-    const LANGUAGE_LEVEL: &'static str = "1.7";
-    let mut args = vec!["-verbose",
-                        "-source",
-                        LANGUAGE_LEVEL,
-                        "-target",
-                        LANGUAGE_LEVEL,
-                        "-d",
-                        "obj",
-                        "-classpath",
-                        &classpath.to_str().expect("no non-utf8 characters in jar path"),
-                        "-sourcepath",
-                        "src"];
-    for valid_java_path in source_files.iter().map(PathBuf::as_path).filter_map(Path::to_str) {
-        args.push(valid_java_path);
-    }
-    let args = args;
-    try!(execute_program_verbosely(at, &javac_path, &args));
-    try!(execute_script(after, at));
+    let args: Vec<_> = options.iter().chain(&source_files).map(String::as_str).collect();
+    try!(with_diagnostics("javac", runner.run(at, &javac_path, &args), parse_javac_output));
+    if runner.produces_outputs() {
+        try!(state.save(at));
+    }
+    try!(execute_script(after, at, runner));
+    Ok(())
+}
+
+/// Removes the classes of previous builds, which would otherwise outlive their deleted sources.
+fn remove_class_files(at: &Path) -> Result<(), (PathBuf, io::Error)> {
+    let obj = at.join("obj");
+    if !obj.is_dir() {
+        return Ok(());
+    }
+    for entry in WalkDir::new(&obj) {
+        let entry = try!(entry.map_err(|err| {
+            (err.path().unwrap_or(&obj).to_owned(), io::Error::from(err))
+        }));
+        if entry.file_type().is_file() &&
+           entry.path().extension().map(|ext| ext == "class").unwrap_or(false) {
+            try!(remove_file(entry.path()).map_err(|err| (entry.path().to_owned(), err)));
+        }
+    }
     Ok(())
 }
+
+/// Returns all javac arguments except for the source files, using the language level of the
+/// `java` section in the context, or `default_level` if there is none.
+fn javac_options(at: &Path, ctx: &Context, default_level: &str, classpath: &str) -> Vec<String> {
+    let java = ctx.java.as_ref();
+    let source = java.and_then(|j| j.source.as_ref()).map(String::as_str).unwrap_or(default_level);
+    let target = java.and_then(|j| j.target.as_ref()).map(String::as_str).unwrap_or(source);
+    let mut options = Vec::new();
+    for arg in &["-verbose", "-source", source, "-target", target, "-d", "obj", "-classpath",
+                 classpath, "-sourcepath", "src"] {
+        options.push((*arg).to_owned());
+    }
+    if let Some(processor_path) = java.and_then(|j| j.processor_path.as_ref()) {
+        let paths: Vec<_> = processor_path.iter().map(|p| path_argument(at, &at.join(p))).collect();
+        options.push("-processorpath".to_owned());
+        options.push(join_paths(&paths)
+            .expect("processor paths with no invalid characters")
+            .to_string_lossy()
+            .into_owned());
+    }
+    options.extend(java.and_then(|j| j.flags.clone()).unwrap_or_default());
+    options
+}
+
+
+#[cfg(unix)]
+#[test]
+fn test_compile_is_recorded_without_sdk_or_jdk() {
+    use std::fs::{File, create_dir_all, remove_dir_all, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use super::{RecordingRunner, Sdk, unique_temp_dir};
+
+    let dir = unique_temp_dir("compile-stage");
+    let (at, sdk_root) = (dir.join("hello"), dir.join("sdk"));
+    let build_tools = sdk_root.join("build-tools/27.0.3");
+    for path in &[build_tools.join("aapt"),
+                  build_tools.join("d8"),
+                  sdk_root.join("platforms/android-25/android.jar"),
+                  at.join(MANIFEST_FILENAME),
+                  at.join("res/values/strings.xml"),
+                  at.join("src/com/example/hello/Main.java")] {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap();
+    }
+    for tool in &["aapt", "d8"] {
+        set_permissions(build_tools.join(tool), Permissions::from_mode(0o755)).unwrap();
+    }
+    let ctx = Context {
+        project: "hello".to_owned(),
+        package: "com.example.hello".to_owned(),
+        target: "android-25".to_owned(),
+        ..Context::default()
+    };
+    let runner = RecordingRunner::assuming_sdk(Sdk::at(sdk_root.clone(), &ctx).unwrap());
+    let res = compile_application(&at, &ctx, &runner);
+    remove_dir_all(&dir).unwrap();
+    res.unwrap();
+
+    let invocations = runner.into_plan().invocations;
+    assert_eq!(invocations.len(), 2);
+    assert_eq!(invocations[0].executable, build_tools.join("aapt").display().to_string());
+    assert_eq!(&invocations[0].args[..2], &["package", "-vfm"]);
+    assert!(invocations[1].executable.ends_with("javac"));
+    assert_eq!(&invocations[1].args[..3], &["-verbose", "-source", "1.8"]);
+    assert_eq!(invocations[1].args.last().map(String::as_str),
+               Some("src/com/example/hello/Main.java"));
+}
@@ -2,9 +2,15 @@ use regex::Regex;
 use std::io::{self, Read};
 use rustc_serialize::json::{decode, as_pretty_json, DecoderError};
 use std::collections::HashMap;
+use std::time::Duration;
+use super::{DX_LANGUAGE_LEVEL, D8_LANGUAGE_LEVEL, BuildToolsVersion};
+use super::logcat::Priority;
 
 const VALID_PROJECT_NAME: &'static str = "^[0-9a-zA-Z]+$";
 const VALID_TARGET_NAME: &'static str = "^[0-9a-zA-Z_-]+$";
+pub const SIGNERS: &'static [&'static str] = &["apksigner", "jarsigner"];
+pub const DEXERS: &'static [&'static str] = &["d8", "dx"];
+pub const SIGNATURE_SCHEMES: &'static [&'static str] = &["v1", "v2", "v3"];
 
 quick_error! {
     #[derive(PartialEq, Eq, Debug)]
@@ -14,6 +20,40 @@ quick_error! {
             display("Target name '{}' is invalid as it does not match '{}'",
                     name, VALID_TARGET_NAME)
         }
+        InvalidPasswordSource (field: String) {
+            description("A password source is invalid")
+            display("Password source '{}' must specify exactly one of 'env' or 'file'", field)
+        }
+        InvalidSigner (name: String) {
+            description("The signer is unknown")
+            display("Signer '{}' is invalid as it is not one of {}", name, SIGNERS.join(", "))
+        }
+        InvalidDexer (name: String) {
+            description("The dexer is unknown")
+            display("Dexer '{}' is invalid as it is not one of {}", name, DEXERS.join(", "))
+        }
+        InvalidBuildToolsVersion (version: String) {
+            description("The build-tools version is invalid")
+            display("Build-tools version '{}' is not of the form 'major.minor.patch'", version)
+        }
+        InvalidLanguageLevel (level: String) {
+            description("The java language level is invalid")
+            display("Java language level '{}' is invalid, use e.g. '1.7' or '8'", level)
+        }
+        UnsupportedLanguageLevel (level: String, max: String) {
+            description("The java language level is not supported by the dexer")
+            display("Java language level '{}' exceeds level '{}' supported by the dexer",
+                    level, max)
+        }
+        InvalidSignatureScheme (name: String) {
+            description("The signature scheme is unknown")
+            display("Signature scheme '{}' is invalid as it is not one of {}",
+                    name, SIGNATURE_SCHEMES.join(", "))
+        }
+        InvalidLogPriority (priority: String) {
+            description("The log priority is unknown")
+            display("Log priority '{}' is invalid, use e.g. 'debug' or 'warn'", priority)
+        }
         InvalidProjectName (name: String) {
             description("The project name is invalid")
             display("Project name '{}' is invalid as it does not match '{}'",
@@ -44,12 +84,86 @@ pub struct Task {
     pub after: Option<String>,
 }
 
+/// Where to obtain a password from, as passwords are never stored in the context itself.
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct PasswordSource {
+    pub env: Option<String>,
+    pub file: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Signing {
+    pub keystore: String,
+    pub alias: String,
+    pub store_password: PasswordSource,
+    pub key_password: Option<PasswordSource>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Java {
+    pub source: Option<String>,
+    pub target: Option<String>,
+    /// Additional flags for javac, like `-Xlint` or `-Werror`.
+    pub flags: Option<Vec<String>>,
+    pub processor_path: Option<Vec<String>>,
+}
+
+/// Limits in seconds for how long programs may run before they are terminated.
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Timeouts {
+    /// Applies to each program on its own.
+    pub command: Option<u64>,
+    /// Applies to all programs of a stage together, keyed by stage name, like `launch`.
+    pub stages: Option<HashMap<String, u64>>,
+}
+
+impl Timeouts {
+    pub fn command_timeout(&self) -> Option<Duration> {
+        self.command.map(Duration::from_secs)
+    }
+
+    pub fn stage_timeout(&self, stage: &str) -> Option<Duration> {
+        self.stages
+            .as_ref()
+            .and_then(|stages| stages.get(stage))
+            .map(|&secs| Duration::from_secs(secs))
+    }
+}
+
+/// Which log lines of the application `logcat` shows.
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Logcat {
+    /// The lowest priority to show, like `info` or `I`.
+    pub priority: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// Returns the major version of a java language level like `1.7` or `8`.
+pub fn language_level_version(level: &str) -> Option<u32> {
+    let level = if level.starts_with("1.") { &level[2..] } else { level };
+    level.parse().ok()
+}
+
 #[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
 pub struct Context {
     pub project: String,
     pub package: String,
     pub target: String,
     pub tasks: HashMap<String, Task>,
+    pub library_dirs: Option<Vec<String>>,
+    pub dependencies: Option<Vec<String>>,
+    pub repository: Option<String>,
+    pub signing: Option<Signing>,
+    pub signer: Option<String>,
+    pub signature_schemes: Option<Vec<String>>,
+    pub dexer: Option<String>,
+    pub min_api: Option<u32>,
+    pub java: Option<Java>,
+    pub build_tools_version: Option<String>,
+    pub timeouts: Option<Timeouts>,
+    /// The serial of the device `launch` installs to, unless another one is chosen.
+    pub default_device: Option<String>,
+    pub logcat: Option<Logcat>,
 }
 
 impl Context {
@@ -64,6 +178,64 @@ impl Context {
         if !re_valid_target_name.is_match(&self.target) {
             return Err(ContextVerificationError::InvalidTargetName(self.target.to_owned()));
         }
+        if let Some(ref signer) = self.signer {
+            if !SIGNERS.contains(&signer.as_str()) {
+                return Err(ContextVerificationError::InvalidSigner(signer.to_owned()));
+            }
+        }
+        if let Some(ref dexer) = self.dexer {
+            if !DEXERS.contains(&dexer.as_str()) {
+                return Err(ContextVerificationError::InvalidDexer(dexer.to_owned()));
+            }
+        }
+        for scheme in self.signature_schemes.iter().flat_map(|s| s.iter()) {
+            if !SIGNATURE_SCHEMES.contains(&scheme.as_str()) {
+                return Err(ContextVerificationError::InvalidSignatureScheme(scheme.to_owned()));
+            }
+        }
+        if let Some(ref version) = self.build_tools_version {
+            if BuildToolsVersion::parse(version).is_none() {
+                return Err(ContextVerificationError::InvalidBuildToolsVersion(version.to_owned()));
+            }
+        }
+        if let Some(priority) = self.logcat.as_ref().and_then(|l| l.priority.as_ref()) {
+            if Priority::parse(priority).is_none() {
+                return Err(ContextVerificationError::InvalidLogPriority(priority.to_owned()));
+            }
+        }
+        let max_language_level = match self.dexer.as_ref().map(String::as_str) {
+            Some("dx") => DX_LANGUAGE_LEVEL,
+            _ => D8_LANGUAGE_LEVEL,
+        };
+        try!(self.verify_language_level(max_language_level));
+        if let Some(ref signing) = self.signing {
+            let sources = Some(("store_password", &signing.store_password))
+                .into_iter()
+                .chain(signing.key_password.as_ref().map(|s| ("key_password", s)));
+            for (field, source) in sources {
+                if source.env.is_some() == source.file.is_some() {
+                    return Err(ContextVerificationError::InvalidPasswordSource(field.to_owned()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails if the language levels of the `java` section are invalid or exceed `max`.
+    pub fn verify_language_level(&self, max: &str) -> Result<(), ContextVerificationError> {
+        let java = match self.java {
+            Some(ref java) => java,
+            None => return Ok(()),
+        };
+        for level in java.source.iter().chain(java.target.iter()) {
+            let version = try!(language_level_version(level).ok_or_else(|| {
+                ContextVerificationError::InvalidLanguageLevel(level.to_owned())
+            }));
+            if Some(version) > language_level_version(max) {
+                return Err(ContextVerificationError::UnsupportedLanguageLevel(level.to_owned(),
+                                                                              max.to_owned()));
+            }
+        }
         Ok(())
     }
 
@@ -0,0 +1,443 @@
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::fmt::{self, Display, Formatter};
+use regex::Regex;
+use rustc_serialize::json::{decode, as_pretty_json};
+use super::{CommandRunner, Context, FindError, get_env_as_path, fingerprint_file};
+
+pub const LOCK_FILENAME: &'static str = "anders.lock";
+const DEFAULT_REPOSITORY: &'static str = ".m2/repository";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ResolutionError {
+        InvalidCoordinate(coordinate: String) {
+            description("A dependency coordinate is invalid")
+            display("Dependency '{}' is not of the form 'group:artifact:version'", coordinate)
+        }
+        MissingVersion(coordinate: String, dependency: String) {
+            description("A dependency has no version")
+            display("The pom of '{}' does not specify a version for its dependency '{}'",
+                    coordinate, dependency)
+        }
+        NotFound(coordinate: String, path: PathBuf) {
+            description("A dependency could not be found in the repository")
+            display("Dependency '{}' was not found at '{}'", coordinate, path.display())
+        }
+        Repository(err: FindError) {
+            description("The repository location could not be determined")
+            from()
+            cause(err)
+        }
+        Io(path: PathBuf, err: io::Error) {
+            description("A file could not be read or written")
+            display("Failed to read or write '{}'", path.display())
+            context(p: &'a Path, err: io::Error) -> (p.to_path_buf(), err)
+            cause(err)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Coordinate {
+    pub group: String,
+    pub artifact: String,
+    pub version: String,
+}
+
+impl Coordinate {
+    pub fn parse(coordinate: &str) -> Result<Coordinate, ResolutionError> {
+        let tokens: Vec<_> = coordinate.split(':').map(str::trim).collect();
+        match tokens.as_slice() {
+            &[group, artifact, version] if !group.is_empty() && !artifact.is_empty() &&
+                                           !version.is_empty() => {
+                Ok(Coordinate {
+                    group: group.to_owned(),
+                    artifact: artifact.to_owned(),
+                    version: version.to_owned(),
+                })
+            }
+            _ => Err(ResolutionError::InvalidCoordinate(coordinate.to_owned())),
+        }
+    }
+
+    /// Returns the path of the file of this artifact with the given extension, like `pom`,
+    /// relative to the repository root.
+    pub fn path(&self, extension: &str) -> PathBuf {
+        Path::new(&self.group.replace(".", "/"))
+            .join(&self.artifact)
+            .join(&self.version)
+            .join(format!("{}-{}.{}", self.artifact, self.version, extension))
+    }
+
+    fn key(&self) -> (String, String) {
+        (self.group.to_owned(), self.artifact.to_owned())
+    }
+}
+
+impl Display for Coordinate {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.group, self.artifact, self.version)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pom {
+    pub packaging: String,
+    pub dependencies: Vec<Coordinate>,
+}
+
+fn capture_tag(tag: &str, xml: &str) -> Option<String> {
+    Regex::new(&format!(r"<{0}>\s*([^<]*?)\s*</{0}>", tag))
+        .expect("valid tag regex")
+        .captures(xml)
+        .and_then(|c| c.at(1).map(str::to_owned))
+}
+
+fn strip_sections(xml: &str, tags: &[&str]) -> String {
+    tags.iter().fold(xml.to_owned(), |xml, tag| {
+        Regex::new(&format!(r"(?s)<{0}>.*?</{0}>", tag))
+            .expect("valid section regex")
+            .replace_all(&xml, "")
+    })
+}
+
+fn interpolate(value: &str, properties: &HashMap<String, String>) -> String {
+    Regex::new(r"\$\{([\w.-]+)\}")
+        .expect("valid property regex")
+        .replace_all(value, |c: &::regex::Captures| {
+            let name = c.at(1).expect("single capture");
+            properties.get(name).cloned().unwrap_or_else(|| format!("${{{}}}", name))
+        })
+}
+
+impl Pom {
+    /// Parses the parts of a pom relevant to dependency resolution. Dependencies of scope `test`,
+    /// `provided` and `system` as well as optional ones are not part of the result, as they are
+    /// not needed at runtime. Parent poms are not consulted.
+    pub fn parse(xml: &str, of: &Coordinate) -> Result<Pom, ResolutionError> {
+        let xml = Regex::new(r"(?s)<!--.*?-->").expect("valid comment regex").replace_all(xml, "");
+        let mut properties = HashMap::new();
+        if let Some(section) = Regex::new(r"(?s)<properties>(.*?)</properties>")
+            .expect("valid properties regex")
+            .captures(&xml)
+            .and_then(|c| c.at(1)) {
+            for c in Regex::new(r"<([\w.-]+)>\s*([^<]*?)\s*</[\w.-]+>")
+                .expect("valid property regex")
+                .captures_iter(section) {
+                properties.insert(c.at(1).expect("name").to_owned(),
+                                  c.at(2).expect("value").to_owned());
+            }
+        }
+        for &(name, value) in &[("project.groupId", &of.group),
+                                ("project.artifactId", &of.artifact),
+                                ("project.version", &of.version),
+                                ("version", &of.version)] {
+            properties.insert(name.to_owned(), value.to_owned());
+        }
+
+        let managed = Regex::new(r"(?s)<dependencyManagement>(.*?)</dependencyManagement>")
+            .expect("valid management regex")
+            .captures(&xml)
+            .and_then(|c| c.at(1).map(str::to_owned))
+            .unwrap_or_default();
+        let project = strip_sections(&xml,
+                                     &["parent", "dependencyManagement", "build", "profiles",
+                                       "reporting"]);
+        let re_dependency = Regex::new(r"(?s)<dependency>(.*?)</dependency>")
+            .expect("valid dependency regex");
+        let managed_version = |group: &str, artifact: &str| {
+            re_dependency.captures_iter(&managed)
+                .filter_map(|c| c.at(1))
+                .find(|d| {
+                    capture_tag("groupId", d).as_ref().map(String::as_str) == Some(group) &&
+                    capture_tag("artifactId", d).as_ref().map(String::as_str) == Some(artifact)
+                })
+                .and_then(|d| capture_tag("version", d))
+        };
+
+        let mut dependencies = Vec::new();
+        for dependency in re_dependency.captures_iter(&project).filter_map(|c| c.at(1)) {
+            let scope = capture_tag("scope", dependency).unwrap_or_else(|| "compile".to_owned());
+            if ["test", "provided", "system"].contains(&scope.as_str()) ||
+               capture_tag("optional", dependency).map(|o| o == "true").unwrap_or(false) {
+                continue;
+            }
+            let group = interpolate(&capture_tag("groupId", dependency).unwrap_or_default(),
+                                    &properties);
+            let artifact = interpolate(&capture_tag("artifactId", dependency).unwrap_or_default(),
+                                       &properties);
+            let version = match capture_tag("version", dependency)
+                .or_else(|| managed_version(&group, &artifact)) {
+                Some(version) => interpolate(&version, &properties),
+                None => {
+                    return Err(ResolutionError::MissingVersion(of.to_string(),
+                                                               format!("{}:{}", group, artifact)))
+                }
+            };
+            dependencies.push(Coordinate {
+                group: group,
+                artifact: artifact,
+                version: version,
+            });
+        }
+
+        Ok(Pom {
+            packaging: capture_tag("packaging", &project).unwrap_or_else(|| "jar".to_owned()),
+            dependencies: dependencies,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct LockedArtifact {
+    pub coordinate: String,
+    /// The path of the artifact relative to the repository, so that the lock can be shared.
+    pub path: String,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct Lock {
+    pub dependencies: Vec<String>,
+    /// The repository as configured in the context, if any.
+    pub repository: Option<String>,
+    pub artifacts: Vec<LockedArtifact>,
+}
+
+impl Lock {
+    pub fn load(at: &Path) -> Option<Lock> {
+        let mut buf = String::new();
+        File::open(at.join(LOCK_FILENAME))
+            .and_then(|mut f| f.read_to_string(&mut buf))
+            .ok()
+            .and_then(|_| decode(&buf).ok())
+    }
+
+    pub fn save(&self, at: &Path) -> Result<(), ResolutionError> {
+        let path = at.join(LOCK_FILENAME);
+        File::create(&path)
+            .and_then(|mut f| write!(f, "{}", as_pretty_json(self)))
+            .map_err(|err| ResolutionError::Io(path.to_owned(), err))
+    }
+
+    /// Returns true if this lock was created for the given dependencies and repository, and all
+    /// of its artifacts are still present and unchanged within `repository_dir`.
+    pub fn is_valid_for(&self,
+                        dependencies: &[String],
+                        repository: &Option<String>,
+                        repository_dir: &Path)
+                        -> bool {
+        self.dependencies.as_slice() == dependencies && &self.repository == repository &&
+        self.artifact_paths(repository_dir)
+            .iter()
+            .zip(&self.artifacts)
+            .all(|(path, a)| fingerprint_file(path).map(|f| f == a.fingerprint).unwrap_or(false))
+    }
+
+    /// Returns the paths of the artifacts within `repository_dir`.
+    pub fn artifact_paths(&self, repository_dir: &Path) -> Vec<PathBuf> {
+        self.artifacts.iter().map(|a| repository_dir.join(&a.path)).collect()
+    }
+}
+
+pub fn repository_dir(at: &Path, ctx: &Context) -> Result<PathBuf, ResolutionError> {
+    Ok(match ctx.repository {
+        Some(ref dir) => at.join(dir),
+        None => try!(get_env_as_path("HOME")).join(DEFAULT_REPOSITORY),
+    })
+}
+
+/// Resolves the given coordinates and all of their transitive dependencies from the maven
+/// repository at `repository`, returning the paths to their artifacts in breadth-first order.
+/// If a group and artifact is encountered more than once, the version nearest to the root wins.
+pub fn resolve(repository: &Path,
+               coordinates: &[Coordinate])
+               -> Result<Vec<(Coordinate, PathBuf)>, ResolutionError> {
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<Coordinate> = coordinates.iter().cloned().collect();
+    let mut artifacts = Vec::new();
+    while let Some(coordinate) = queue.pop_front() {
+        if !seen.insert(coordinate.key()) {
+            continue;
+        }
+        let pom_path = repository.join(coordinate.path("pom"));
+        let pom = if pom_path.is_file() {
+            let mut xml = String::new();
+            try!(File::open(&pom_path)
+                .and_then(|mut f| f.read_to_string(&mut xml))
+                .map_err(|err| ResolutionError::Io(pom_path.to_owned(), err)));
+            try!(Pom::parse(&xml, &coordinate))
+        } else {
+            Pom {
+                packaging: "jar".to_owned(),
+                dependencies: Vec::new(),
+            }
+        };
+        let extension = if pom.packaging == "aar" { "aar" } else { "jar" };
+        let artifact_path = repository.join(coordinate.path(extension));
+        if !artifact_path.is_file() {
+            return Err(ResolutionError::NotFound(coordinate.to_string(), artifact_path));
+        }
+        queue.extend(pom.dependencies);
+        artifacts.push((coordinate, artifact_path));
+    }
+    Ok(artifacts)
+}
+
+/// Returns the artifacts of all dependencies declared in the context, using the lock file next
+/// to the context if it is still valid, or resolving them and writing a new lock file otherwise.
+/// The lock file is only written if the `runner` produces outputs.
+pub fn dependency_artifacts(at: &Path,
+                            ctx: &Context,
+                            runner: &CommandRunner)
+                            -> Result<Vec<PathBuf>, ResolutionError> {
+    let declared = match ctx.dependencies {
+        Some(ref dependencies) if !dependencies.is_empty() => dependencies,
+        _ => return Ok(Vec::new()),
+    };
+    let repository = try!(repository_dir(at, ctx));
+    if let Some(lock) = Lock::load(at) {
+        if lock.is_valid_for(declared, &ctx.repository, &repository) {
+            return Ok(lock.artifact_paths(&repository));
+        }
+    }
+
+    let coordinates = try!(declared.iter()
+        .map(|c| Coordinate::parse(c))
+        .collect::<Result<Vec<_>, _>>());
+    let resolved = try!(resolve(&repository, &coordinates));
+    let mut lock = Lock {
+        dependencies: declared.clone(),
+        repository: ctx.repository.clone(),
+        artifacts: Vec::with_capacity(resolved.len()),
+    };
+    for (coordinate, path) in resolved {
+        lock.artifacts.push(LockedArtifact {
+            coordinate: coordinate.to_string(),
+            fingerprint: try!(fingerprint_file(&path)
+                .map_err(|err| ResolutionError::Io(path.to_owned(), err))),
+            path: path.strip_prefix(&repository).unwrap_or(&path).to_string_lossy().into_owned(),
+        });
+    }
+    if runner.produces_outputs() {
+        try!(lock.save(at));
+    }
+    Ok(lock.artifact_paths(&repository))
+}
+
+
+#[test]
+fn test_coordinate_parse() {
+    assert_eq!(Coordinate::parse("com.squareup:otto:1.3.8").map(|c| c.path("pom")).ok(),
+               Some(PathBuf::from("com/squareup/otto/1.3.8/otto-1.3.8.pom")));
+    assert!(Coordinate::parse("com.squareup:otto").is_err());
+    assert!(Coordinate::parse("com.squareup::1.0").is_err());
+}
+
+#[test]
+fn test_pom_parse() {
+    let of = Coordinate::parse("a:b:1.0").unwrap();
+    let pom = Pom::parse(r#"
+        <project>
+          <parent><groupId>p</groupId><artifactId>parent</artifactId></parent>
+          <packaging>aar</packaging>
+          <properties><dep.version>2.1</dep.version></properties>
+          <dependencyManagement><dependencies><dependency>
+            <groupId>m</groupId><artifactId>managed</artifactId><version>3.0</version>
+          </dependency></dependencies></dependencyManagement>
+          <dependencies>
+            <dependency>
+              <groupId>x</groupId><artifactId>y</artifactId><version>${dep.version}</version>
+            </dependency>
+            <dependency><groupId>m</groupId><artifactId>managed</artifactId></dependency>
+            <dependency>
+              <groupId>${project.groupId}</groupId><artifactId>c</artifactId>
+              <version>${project.version}</version>
+            </dependency>
+            <!-- <dependency><groupId>no</groupId><artifactId>no</artifactId></dependency> -->
+            <dependency>
+              <groupId>t</groupId><artifactId>junit</artifactId><version>4</version>
+              <scope>test</scope>
+            </dependency>
+            <dependency>
+              <groupId>o</groupId><artifactId>opt</artifactId><version>1</version>
+              <optional>true</optional>
+            </dependency>
+          </dependencies>
+        </project>"#,
+                         &of)
+        .unwrap();
+    assert_eq!(pom.packaging, "aar");
+    assert_eq!(pom.dependencies.iter().map(ToString::to_string).collect::<Vec<_>>(),
+               vec!["x:y:2.1", "m:managed:3.0", "a:c:1.0"]);
+}
+
+
+#[test]
+fn test_dependency_artifacts() {
+    use std::fs::{create_dir_all, remove_dir_all, remove_file};
+    use super::{RecordingRunner, SpawningRunner, unique_temp_dir};
+
+    fn publish(repository: &Path, coordinate: &str, packaging: &str, dependencies: &[&str]) {
+        let parsed = Coordinate::parse(coordinate).unwrap();
+        let pom_path = repository.join(parsed.path("pom"));
+        create_dir_all(pom_path.parent().unwrap()).unwrap();
+        let mut pom = format!("<project><packaging>{}</packaging><dependencies>", packaging);
+        for dependency in dependencies {
+            let c = Coordinate::parse(dependency).unwrap();
+            pom.push_str(&format!("<dependency><groupId>{}</groupId><artifactId>{}</artifactId>\
+                                   <version>{}</version></dependency>",
+                                  c.group,
+                                  c.artifact,
+                                  c.version));
+        }
+        pom.push_str("</dependencies></project>");
+        File::create(pom_path).unwrap().write_all(pom.as_bytes()).unwrap();
+        File::create(repository.join(parsed.path(packaging)))
+            .unwrap()
+            .write_all(coordinate.as_bytes())
+            .unwrap();
+    }
+
+    let at = unique_temp_dir("dependencies");
+    let repository = at.join("repo");
+    publish(&repository, "a:app:1.0", "jar", &["b:lib:1.0", "c:util:2.0"]);
+    publish(&repository, "b:lib:1.0", "jar", &["c:util:1.0", "d:deep:1.0"]);
+    publish(&repository, "c:util:1.0", "jar", &[]);
+    publish(&repository, "c:util:2.0", "jar", &[]);
+    publish(&repository, "d:deep:1.0", "aar", &[]);
+    let mut ctx = Context {
+        dependencies: Some(vec!["a:app:1.0".to_owned()]),
+        repository: Some("repo".to_owned()),
+        ..Context::default()
+    };
+    let expected = vec![repository.join("a/app/1.0/app-1.0.jar"),
+                        repository.join("b/lib/1.0/lib-1.0.jar"),
+                        repository.join("c/util/2.0/util-2.0.jar"),
+                        repository.join("d/deep/1.0/deep-1.0.aar")];
+
+    let recorded = dependency_artifacts(&at, &ctx, &RecordingRunner::new()).unwrap();
+    let lock_after_recording = Lock::load(&at);
+    let resolved = dependency_artifacts(&at, &ctx, &SpawningRunner::default()).unwrap();
+    let lock = Lock::load(&at).unwrap();
+    // without the pom of b, resolving again would miss d, so it has to come from the lock
+    remove_file(repository.join("b/lib/1.0/lib-1.0.pom")).unwrap();
+    let locked = dependency_artifacts(&at, &ctx, &SpawningRunner::default()).unwrap();
+    ctx.repository = Some("elsewhere".to_owned());
+    let elsewhere = dependency_artifacts(&at, &ctx, &SpawningRunner::default());
+    remove_dir_all(&at).unwrap();
+
+    assert_eq!(recorded, expected);
+    assert_eq!(lock_after_recording, None);
+    assert_eq!(resolved, expected);
+    assert_eq!(lock.repository, Some("repo".to_owned()));
+    assert_eq!(lock.artifacts[0].path, "a/app/1.0/app-1.0.jar");
+    assert_eq!(locked, expected);
+    match elsewhere {
+        Err(ResolutionError::NotFound(coordinate, _)) => assert_eq!(coordinate, "a:app:1.0"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
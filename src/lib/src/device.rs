@@ -0,0 +1,347 @@
+use std::env;
+use std::path::Path;
+use std::fmt::{self, Display, Formatter};
+use regex::Regex;
+use super::{BatchExecutionError, CommandRunner, Context};
+
+/// The environment variable `adb` reads the serial of the device to use from.
+pub const SERIAL_ENV_VAR: &'static str = "ANDROID_SERIAL";
+
+/// The state `adb` reports for devices which accept commands.
+const READY_STATE: &'static str = "device";
+
+/// A device or emulator as listed by `adb devices -l`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub serial: String,
+    /// Like `device`, `offline` or `unauthorized`.
+    pub state: String,
+    /// Set for devices connected by USB.
+    pub usb: Option<String>,
+    pub model: Option<String>,
+}
+
+impl Device {
+    pub fn is_ready(&self) -> bool {
+        self.state == READY_STATE
+    }
+
+    pub fn is_emulator(&self) -> bool {
+        self.serial.starts_with("emulator-")
+    }
+
+    pub fn is_usb(&self) -> bool {
+        self.usb.is_some()
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(write!(f, "{} ({}", self.serial, self.state));
+        if let Some(ref model) = self.model {
+            try!(write!(f, ", {}", model));
+        }
+        write!(f, ")")
+    }
+}
+
+/// Parses lines like `emulator-5554 device product:sdk_x86 model:Android_SDK_x86`, skipping
+/// the header and messages about starting the `adb` server.
+pub fn parse_adb_devices(lines: &[String]) -> Vec<Device> {
+    lines.iter()
+        .filter(|line| !line.starts_with('*') && !line.starts_with("List of devices"))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (serial, state) = match (fields.next(), fields.next()) {
+                (Some(serial), Some(state)) => (serial, state),
+                _ => return None,
+            };
+            let mut device = Device {
+                serial: serial.to_owned(),
+                state: state.to_owned(),
+                usb: None,
+                model: None,
+            };
+            for field in fields {
+                if field.starts_with("usb:") {
+                    device.usb = Some(field["usb:".len()..].to_owned());
+                } else if field.starts_with("model:") {
+                    device.model = Some(field["model:".len()..].to_owned());
+                }
+            }
+            Some(device)
+        })
+        .collect()
+}
+
+/// Returns the id of the process running `package` on the device selected by `device_args`, or
+/// `None` if it isn't running.
+pub fn application_pid(at: &Path,
+                       adb_path: &Path,
+                       device_args: &[&str],
+                       package: &str,
+                       runner: &CommandRunner)
+                       -> Option<u32> {
+    // pidof fails while the process doesn't exist
+    runner.run_captured(at, adb_path, &[device_args, &["shell", "pidof", package]].concat())
+        .ok()
+        .and_then(|output| output.iter().filter_map(|l| l.trim().parse().ok()).next())
+}
+
+/// Why `adb install` failed, as reported by the package manager of the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstallFailure {
+    /// An installed package with the same name is signed with a different key.
+    UpdateIncompatible,
+    /// The package shares its user id with an installed package signed with a different key.
+    SharedUserIncompatible,
+    /// The installed package has a higher version code.
+    VersionDowngrade,
+    InsufficientStorage,
+    /// The device runs an older Android version than the package requires.
+    OlderSdk,
+    /// The package isn't signed, or its signature doesn't match its contents.
+    InvalidSignature,
+    Other(String),
+}
+
+impl InstallFailure {
+    fn from_code(code: &str) -> InstallFailure {
+        match code {
+            "INSTALL_FAILED_UPDATE_INCOMPATIBLE" => InstallFailure::UpdateIncompatible,
+            "INSTALL_FAILED_SHARED_USER_INCOMPATIBLE" => InstallFailure::SharedUserIncompatible,
+            "INSTALL_FAILED_VERSION_DOWNGRADE" => InstallFailure::VersionDowngrade,
+            "INSTALL_FAILED_INSUFFICIENT_STORAGE" => InstallFailure::InsufficientStorage,
+            "INSTALL_FAILED_OLDER_SDK" => InstallFailure::OlderSdk,
+            "INSTALL_PARSE_FAILED_NO_CERTIFICATES" |
+            "INSTALL_PARSE_FAILED_INCONSISTENT_CERTIFICATES" => InstallFailure::InvalidSignature,
+            code => InstallFailure::Other(code.to_owned()),
+        }
+    }
+}
+
+impl Display for InstallFailure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            InstallFailure::UpdateIncompatible => {
+                write!(f, "the installed package is signed with a different key")
+            }
+            InstallFailure::SharedUserIncompatible => {
+                write!(f,
+                       "a package sharing the user id is signed with a different key, which \
+                        uninstalling this package doesn't fix")
+            }
+            InstallFailure::VersionDowngrade => {
+                write!(f, "the installed package has a higher version code")
+            }
+            InstallFailure::InsufficientStorage => write!(f, "the device is out of storage"),
+            InstallFailure::OlderSdk => {
+                write!(f, "the device runs an older Android version than the package requires")
+            }
+            InstallFailure::InvalidSignature => write!(f, "the package is not signed correctly"),
+            InstallFailure::Other(ref code) => write!(f, "{}", code),
+        }
+    }
+}
+
+/// Finds the failure in the output of `adb install`, like
+/// `adb: failed to install app.apk: Failure [INSTALL_FAILED_OLDER_SDK: Requires newer sdk]`.
+/// Older versions of `adb` exit successfully even then.
+pub fn parse_install_failure(lines: &[String]) -> Option<(InstallFailure, String)> {
+    let re_failure = Regex::new(r"Failure \[([A-Z_]+)(?:: ([^\]]*))?\]")
+        .expect("valid install failure regex");
+    lines.iter().filter_map(|line| re_failure.captures(line)).next().map(|c| {
+        (InstallFailure::from_code(c.at(1).unwrap_or("")), c.at(2).unwrap_or("").to_owned())
+    })
+}
+
+fn device_list(devices: &[Device]) -> String {
+    if devices.is_empty() {
+        return ", and no devices are connected".to_owned();
+    }
+    let lines: Vec<_> = devices.iter().map(|d| format!("  {}", d)).collect();
+    format!(". Connected devices:\n{}", lines.join("\n"))
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum DeviceError {
+        NoDevice(wanted: String, connected: Vec<Device>) {
+            description("No matching device is connected")
+            display("No {} is ready{}", wanted, device_list(connected))
+        }
+        NotConnected(serial: String, connected: Vec<Device>) {
+            description("The chosen device is not connected")
+            display("Device '{}' is not connected{}", serial, device_list(connected))
+        }
+        NotReady(device: Device) {
+            description("The chosen device does not accept commands")
+            display("Device '{}' is {}, but needs to be ready to accept commands",
+                    device.serial, device.state)
+        }
+        Ambiguous(wanted: String, candidates: Vec<Device>) {
+            description("More than one matching device is connected")
+            display("More than one {} is ready, choose one with --device <serial>{}",
+                    wanted, device_list(candidates))
+        }
+        Install(failure: InstallFailure, detail: String) {
+            description("The package could not be installed")
+            display("Failed to install the package, as {}{}",
+                    failure,
+                    if detail.is_empty() { String::new() } else { format!(": {}", detail) })
+        }
+        Start(activity: String, message: String) {
+            description("The activity could not be started")
+            display("Failed to start activity '{}': {}", activity, message)
+        }
+        NoProcess(package: String) {
+            description("The process of the application could not be found")
+            display("The process of '{}' could not be found to attach a debugger to", package)
+        }
+    }
+}
+
+/// Which device `launch` installs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The only device or emulator which is ready.
+    Any,
+    Usb,
+    Emulator,
+    Serial(String),
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        DeviceSelector::Any
+    }
+}
+
+impl DeviceSelector {
+    /// Returns the device named by `ANDROID_SERIAL`, or else the `default_device` of the context.
+    pub fn configured(ctx: &Context) -> DeviceSelector {
+        match env::var(SERIAL_ENV_VAR) {
+            Ok(ref serial) if !serial.is_empty() => DeviceSelector::Serial(serial.to_owned()),
+            _ => {
+                ctx.default_device
+                    .clone()
+                    .map(DeviceSelector::Serial)
+                    .unwrap_or(DeviceSelector::Any)
+            }
+        }
+    }
+
+    fn description(&self) -> String {
+        match *self {
+            DeviceSelector::Any => "device or emulator".to_owned(),
+            DeviceSelector::Usb => "USB device".to_owned(),
+            DeviceSelector::Emulator => "emulator".to_owned(),
+            DeviceSelector::Serial(ref serial) => format!("device '{}'", serial),
+        }
+    }
+
+    /// Returns the `adb` arguments selecting the device without knowing which are connected,
+    /// leaving the choice to `adb`.
+    pub fn adb_args(&self) -> Vec<&str> {
+        match *self {
+            DeviceSelector::Any => Vec::new(),
+            DeviceSelector::Usb => vec!["-d"],
+            DeviceSelector::Emulator => vec!["-e"],
+            DeviceSelector::Serial(ref serial) => vec!["-s", serial],
+        }
+    }
+
+    /// Returns a selector for the serial of the chosen device among those `adb devices` lists,
+    /// to fail with a helpful error if the choice is ambiguous. If `runner` only records the
+    /// programs it would run, the choice is left to `adb` instead.
+    pub fn resolve(&self,
+                   at: &Path,
+                   adb_path: &Path,
+                   runner: &CommandRunner)
+                   -> Result<DeviceSelector, BatchExecutionError> {
+        if !runner.produces_outputs() {
+            return Ok(self.clone());
+        }
+        let output = try!(runner.run_captured(at, adb_path, &["devices", "-l"]));
+        let devices = parse_adb_devices(&output);
+        let serial = try!(self.select(&devices)).serial.clone();
+        Ok(DeviceSelector::Serial(serial))
+    }
+
+    /// Returns the one device among `devices` this selector chooses.
+    pub fn select<'a>(&self, devices: &'a [Device]) -> Result<&'a Device, DeviceError> {
+        if let DeviceSelector::Serial(ref serial) = *self {
+            return match devices.iter().find(|d| &d.serial == serial) {
+                Some(device) if device.is_ready() => Ok(device),
+                Some(device) => Err(DeviceError::NotReady(device.clone())),
+                None => Err(DeviceError::NotConnected(serial.to_owned(), devices.to_vec())),
+            };
+        }
+        let candidates: Vec<_> = devices.iter()
+            .filter(|d| d.is_ready())
+            .filter(|d| match *self {
+                DeviceSelector::Usb => d.is_usb(),
+                DeviceSelector::Emulator => d.is_emulator(),
+                _ => true,
+            })
+            .collect();
+        match candidates.len() {
+            0 => Err(DeviceError::NoDevice(self.description(), devices.to_vec())),
+            1 => Ok(candidates[0]),
+            _ => {
+                Err(DeviceError::Ambiguous(self.description(),
+                                           candidates.into_iter().cloned().collect()))
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_device_selection() {
+    let output: Vec<_> = r#"* daemon not running; starting now at tcp:5037
+* daemon started successfully
+List of devices attached
+emulator-5554          device product:sdk_gphone_x86 model:Android_SDK_x86 transport_id:1
+0123456789ABCDEF       device usb:1-1 product:blueline model:Pixel_3 device:blueline
+emulator-5556          offline transport_id:3
+"#
+        .lines()
+        .map(String::from)
+        .collect();
+    let devices = parse_adb_devices(&output);
+    assert_eq!(devices.len(), 3);
+    assert_eq!(devices[1].to_string(), "0123456789ABCDEF (device, Pixel_3)");
+
+    assert_eq!(DeviceSelector::Usb.select(&devices).unwrap().serial, "0123456789ABCDEF");
+    assert_eq!(DeviceSelector::Emulator.select(&devices).unwrap().serial, "emulator-5554");
+    match DeviceSelector::Any.select(&devices) {
+        Err(DeviceError::Ambiguous(_, candidates)) => assert_eq!(candidates.len(), 2),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match DeviceSelector::Serial("emulator-5556".to_owned()).select(&devices) {
+        Err(DeviceError::NotReady(device)) => assert_eq!(device.state, "offline"),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn test_parse_install_failure() {
+    let output = vec!["Performing Streamed Install".to_owned(),
+                      "adb: failed to install bin/App.apk: Failure \
+                       [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package com.app signatures do not \
+                       match the previously installed version; ignoring!]"
+                          .to_owned()];
+    let (failure, detail) = parse_install_failure(&output).unwrap();
+    assert_eq!(failure, InstallFailure::UpdateIncompatible);
+    assert!(detail.starts_with("Package com.app signatures"));
+    assert_eq!(parse_install_failure(&["Failure [INSTALL_FAILED_SHARED_USER_INCOMPATIBLE]"
+                                           .to_owned()])
+                   .unwrap()
+                   .0,
+               InstallFailure::SharedUserIncompatible);
+    assert_eq!(parse_install_failure(&["Failure [INSTALL_FAILED_NO_MATCHING_ABIS]".to_owned()]),
+               Some((InstallFailure::Other("INSTALL_FAILED_NO_MATCHING_ABIS".to_owned()),
+                     String::new())));
+    assert_eq!(parse_install_failure(&["Success".to_owned()]), None);
+}
@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+use std::io;
+use quick_error::ResultExt;
+use walkdir::WalkDir;
+use super::{Context, ContextVerificationError, BatchExecutionError, LibraryPath, Sdk,
+            path_argument};
+use super::package::Profile;
+
+pub const DX_LANGUAGE_LEVEL: &'static str = "1.7";
+pub const D8_LANGUAGE_LEVEL: &'static str = "1.8";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dexer {
+    /// The current dexer, which desugars java 8 language features.
+    D8(PathBuf),
+    /// The deprecated dexer, which is gone from current build-tools.
+    Dx(PathBuf),
+}
+
+impl Dexer {
+    /// Returns the dexer selected in the context, or `d8` if it is available and `dx` otherwise.
+    pub fn for_sdk(sdk: &Sdk, ctx: &Context) -> Result<Dexer, BatchExecutionError> {
+        let d8 = || sdk.build_tool("d8").map(Dexer::D8);
+        let dx = || sdk.build_tool("dx").map(Dexer::Dx);
+        Ok(try!(match ctx.dexer.as_ref().map(String::as_str) {
+            Some("dx") => dx(),
+            Some("d8") => d8(),
+            Some(name) => {
+                return Err(ContextVerificationError::InvalidDexer(name.to_owned()).into())
+            }
+            None => d8().or_else(|_| dx()),
+        }))
+    }
+
+    pub fn executable(&self) -> &Path {
+        match *self {
+            Dexer::D8(ref exe) | Dexer::Dx(ref exe) => exe,
+        }
+    }
+
+    /// Returns the highest java language level the dexer can process.
+    pub fn max_language_level(&self) -> &'static str {
+        match *self {
+            Dexer::D8(_) => D8_LANGUAGE_LEVEL,
+            Dexer::Dx(_) => DX_LANGUAGE_LEVEL,
+        }
+    }
+
+    /// Returns the arguments to dex all classes in `obj` and the given `jars` into
+    /// `bin/classes.dex`.
+    pub fn args(&self,
+                at: &Path,
+                ctx: &Context,
+                android_jar_path: &str,
+                profile: Profile,
+                jars: &[PathBuf])
+                -> Result<Vec<String>, BatchExecutionError> {
+        let owned = |args: &[&str]| args.iter().cloned().map(String::from).collect::<Vec<_>>();
+        let mut args;
+        match *self {
+            Dexer::Dx(_) => {
+                args = owned(&["--dex", "--verbose", "--output", "bin/classes.dex", "obj"]);
+            }
+            Dexer::D8(_) => {
+                let mode = match profile {
+                    Profile::Debug => "--debug",
+                    Profile::Release => "--release",
+                };
+                args = owned(&["--lib", android_jar_path, mode, "--output", "bin"]);
+                if let Some(min_api) = ctx.min_api {
+                    args.push("--min-api".to_owned());
+                    args.push(min_api.to_string());
+                }
+                args.extend(try!(class_files(at, "obj")));
+            }
+        }
+        args.extend(jars.iter().map(|jar| path_argument(at, jar)));
+        Ok(args)
+    }
+}
+
+/// Returns all class files below `dir`, sorted, as `d8` does not accept directories as input.
+fn class_files(at: &Path, dir: &str) -> Result<Vec<String>, BatchExecutionError> {
+    let root = at.join(dir);
+    let mut files = Vec::new();
+    if !root.is_dir() {
+        return Ok(files);
+    }
+    for entry in WalkDir::new(&root) {
+        let entry = try!(entry.map_err(io::Error::from).context(LibraryPath(&root)));
+        if entry.file_type().is_file() &&
+           entry.path().extension().map(|ext| ext == "class").unwrap_or(false) {
+            files.push(path_argument(at, entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
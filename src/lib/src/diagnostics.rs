@@ -0,0 +1,216 @@
+use std::fmt::{self, Display, Formatter};
+use regex::{Regex, Captures};
+use super::{BatchExecutionError, ExecutionError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    fn parse(severity: &str) -> Severity {
+        match &*severity.to_lowercase() {
+            "warning" => Severity::Warning,
+            "note" => Severity::Note,
+            _ => Severity::Error,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+/// A problem reported by `javac` or `aapt`. Lines and columns start at 1, and `file` is as
+/// printed by the tool, usually relative to the project root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            try!(write!(f, "{}:", file));
+            if let Some(line) = self.line {
+                try!(write!(f, "{}:", line));
+            }
+            if let Some(column) = self.column {
+                try!(write!(f, "{}:", column));
+            }
+            try!(write!(f, " "));
+        }
+        write!(f, "{}: {}", self.severity.as_str(), self.message)
+    }
+}
+
+/// Returns the diagnostics as one line each, as printed by compilers.
+pub fn format_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter().map(Diagnostic::to_string).collect::<Vec<_>>().join("\n")
+}
+
+fn number(captures: &Captures, index: usize) -> Option<u32> {
+    captures.at(index).and_then(|n| n.parse().ok())
+}
+
+/// Parses diagnostics like `src/com/app/Main.java:12: error: cannot find symbol`. The column is
+/// taken from the line with the caret `javac` prints below the offending source line, and
+/// indented lines after that, like `symbol: class Foo`, are added to the message.
+pub fn parse_javac_output(lines: &[String]) -> Vec<Diagnostic> {
+    let re_located = Regex::new(r"^(.+?\.java):(\d+): (error|warning): (.*)$")
+        .expect("valid javac regex");
+    let re_global = Regex::new(r"^(error|warning): (.*)$").expect("valid javac regex");
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut source_line: Option<&str> = None;
+    for line in lines {
+        if let Some(c) = re_located.captures(line) {
+            diagnostics.push(Diagnostic {
+                file: c.at(1).map(str::to_owned),
+                line: number(&c, 2),
+                column: None,
+                severity: Severity::parse(c.at(3).unwrap_or("error")),
+                message: c.at(4).unwrap_or("").to_owned(),
+            });
+            source_line = None;
+            continue;
+        }
+        if let Some(c) = re_global.captures(line) {
+            diagnostics.push(Diagnostic {
+                file: None,
+                line: None,
+                column: None,
+                severity: Severity::parse(c.at(1).unwrap_or("error")),
+                message: c.at(2).unwrap_or("").to_owned(),
+            });
+            continue;
+        }
+        let last = match diagnostics.last_mut() {
+            Some(last) if last.file.is_some() => last,
+            _ => continue,
+        };
+        if last.column.is_none() {
+            if line.trim() == "^" && source_line.is_some() {
+                last.column = line.find('^').map(|pos| line[..pos].chars().count() as u32 + 1);
+            } else {
+                source_line = Some(line);
+            }
+        } else if line.starts_with("  ") && !line.trim().is_empty() {
+            last.message.push_str(", ");
+            last.message.push_str(line.trim());
+        }
+    }
+    diagnostics
+}
+
+/// Parses diagnostics like `res/layout/main.xml:5: error: Error: No resource found ...`, as well
+/// as errors which aren't tied to a file, like `ERROR: Unable to open ...`.
+pub fn parse_aapt_output(lines: &[String]) -> Vec<Diagnostic> {
+    let re_located = Regex::new(concat!(r"^(.+?):(\d+)(?::(\d+))?: (?i:(error|warning|note)): ",
+                                        r"(?:(?i:error|warning): )?(.*)$"))
+        .expect("valid aapt regex");
+    let re_global = Regex::new(r"^(ERROR|WARNING)(?: at line (\d+))?: (.*)$")
+        .expect("valid aapt regex");
+    lines.iter()
+        .filter_map(|line| {
+            if let Some(c) = re_located.captures(line) {
+                return Some(Diagnostic {
+                    file: c.at(1).map(str::to_owned),
+                    line: number(&c, 2),
+                    column: number(&c, 3),
+                    severity: Severity::parse(c.at(4).unwrap_or("error")),
+                    message: c.at(5).unwrap_or("").to_owned(),
+                });
+            }
+            re_global.captures(line).map(|c| {
+                Diagnostic {
+                    file: None,
+                    line: number(&c, 2),
+                    column: None,
+                    severity: Severity::parse(c.at(1).unwrap_or("error")),
+                    message: c.at(3).unwrap_or("").to_owned(),
+                }
+            })
+        })
+        .collect()
+}
+
+/// Turns a failed invocation of `tool` into `BatchExecutionError::Diagnostics` if `parse` finds
+/// any in its output.
+pub fn with_diagnostics<F>(tool: &str,
+                           res: Result<(), ExecutionError>,
+                           parse: F)
+                           -> Result<(), BatchExecutionError>
+    where F: Fn(&[String]) -> Vec<Diagnostic>
+{
+    match res {
+        Err(err) => {
+            let diagnostics = match err {
+                ExecutionError::Exit { ref output, .. } => parse(output),
+                ExecutionError::Spawn { .. } |
+                ExecutionError::Timeout { .. } |
+                ExecutionError::Interrupted { .. } => Vec::new(),
+            };
+            if diagnostics.is_empty() {
+                Err(err.into())
+            } else {
+                Err(BatchExecutionError::Diagnostics(tool.to_owned(), diagnostics, err))
+            }
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+
+#[test]
+fn test_parse_javac_output() {
+    let output: Vec<_> = r#"[parsing started src/com/app/Main.java]
+src/com/app/Main.java:12: error: cannot find symbol
+        Foo foo = null;
+        ^
+  symbol:   class Foo
+  location: class Main
+src/com/app/Other.java:3: warning: [deprecation] Bar in com.lib has been deprecated
+import com.lib.Bar;
+              ^
+error: invalid target release: 1.9
+1 error"#
+        .lines()
+        .map(String::from)
+        .collect();
+    let diagnostics = parse_javac_output(&output);
+    assert_eq!(format_diagnostics(&diagnostics),
+               "src/com/app/Main.java:12:9: error: cannot find symbol, symbol:   class Foo, \
+                location: class Main
+src/com/app/Other.java:3:15: warning: [deprecation] Bar in com.lib has been deprecated
+error: invalid target release: 1.9");
+}
+
+#[test]
+fn test_parse_aapt_output() {
+    let output: Vec<_> = concat!("Configurations:\n",
+                                 "res/layout/main.xml:5: error: Error: No resource found that ",
+                                 "matches the given name (at 'text' with value '@string/missing').",
+                                 r#"
+AndroidManifest.xml:12: Warning: Resource entry is not public
+ERROR: Unable to open class file src/com/app/R.java: No such file or directory"#)
+        .lines()
+        .map(String::from)
+        .collect();
+    let diagnostics = parse_aapt_output(&output);
+    assert_eq!(diagnostics.len(), 3);
+    assert_eq!(diagnostics[0].to_string(),
+               "res/layout/main.xml:5: error: No resource found that matches the given name (at \
+                'text' with value '@string/missing').");
+    assert_eq!(diagnostics[1].severity, Severity::Warning);
+    assert_eq!(diagnostics[2].file, None);
+}
@@ -0,0 +1,382 @@
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_dir;
+use rustc_serialize::json::{Json, ToJson};
+use super::{CommandRunner, Context, Sdk, Dexer, FindError, find_file_in_path, get_env_as_path,
+            android_platform_jar_path};
+use super::package::debug_keystore_path;
+
+pub const COMMAND_NAME: &'static str = "doctor";
+
+/// How long a tool may take to print its version, unless a timeout is configured.
+pub const VERSION_TIMEOUT_SECS: u64 = 10;
+
+const JAVA_HINT: &'static str = "Install a JDK and make sure its 'bin' directory is in the PATH";
+const ANDROID_HOME_HINT: &'static str = "Set ANDROID_HOME to the directory the Android SDK is \
+                                         installed in";
+
+/// The outcome of checking a single part of the toolchain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    /// True if something is missing which a build can do without.
+    pub warning: bool,
+    pub path: Option<String>,
+    pub version: Option<String>,
+    pub detail: Option<String>,
+    /// What to do about a failed check.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn passed(name: &str, path: Option<&Path>, version: Option<String>) -> Check {
+        Check {
+            name: name.to_owned(),
+            ok: true,
+            warning: false,
+            path: path.map(|p| p.display().to_string()),
+            version: version,
+            detail: None,
+            hint: None,
+        }
+    }
+
+    fn failed<D: Display>(name: &str, detail: D, hint: &str) -> Check {
+        Check {
+            name: name.to_owned(),
+            ok: false,
+            warning: false,
+            path: None,
+            version: None,
+            detail: Some(detail.to_string()),
+            hint: Some(hint.to_owned()),
+        }
+    }
+
+    fn warned<D: Display>(name: &str, detail: D, hint: &str) -> Check {
+        Check {
+            ok: true,
+            warning: true,
+            ..Check::failed(name, detail, hint)
+        }
+    }
+
+    fn with_detail(mut self, detail: String) -> Check {
+        self.detail = Some(detail);
+        self
+    }
+
+    fn of_tool(name: &str,
+               found: Result<PathBuf, FindError>,
+               version: Option<String>,
+               hint: &str)
+               -> Check {
+        match found {
+            Ok(path) => Check::passed(name, Some(&path), version),
+            Err(err) => Check::failed(name, err, hint),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let status = match (self.ok, self.warning) {
+            (true, false) => "[ok]  ",
+            (true, true) => "[warn]",
+            (false, _) => "[FAIL]",
+        };
+        try!(write!(f, "{} {}", status, self.name));
+        if let Some(ref path) = self.path {
+            try!(write!(f, " at '{}'", path));
+        }
+        if let Some(ref version) = self.version {
+            try!(write!(f, " ({})", version));
+        }
+        if let Some(ref detail) = self.detail {
+            try!(write!(f, ": {}", detail));
+        }
+        if let Some(ref hint) = self.hint {
+            try!(write!(f, "\n       hint: {}", hint));
+        }
+        Ok(())
+    }
+}
+
+impl ToJson for Check {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("name".to_owned(), self.name.to_json());
+        object.insert("ok".to_owned(), self.ok.to_json());
+        object.insert("warning".to_owned(), self.warning.to_json());
+        object.insert("path".to_owned(), self.path.to_json());
+        object.insert("version".to_owned(), self.version.to_json());
+        object.insert("detail".to_owned(), self.detail.to_json());
+        object.insert("hint".to_owned(), self.hint.to_json());
+        Json::Object(object)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub android_home: Option<String>,
+    pub build_tools_version: Option<String>,
+    pub checks: Vec<Check>,
+}
+
+impl Diagnosis {
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.ok)
+    }
+
+    pub fn to_pretty_json(&self) -> String {
+        self.to_json().pretty().to_string()
+    }
+}
+
+/// The json form shared by `doctor --json` and the `diagnosis` event.
+impl ToJson for Diagnosis {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("android_home".to_owned(), self.android_home.to_json());
+        object.insert("build_tools_version".to_owned(),
+                      self.build_tools_version.to_json());
+        object.insert("healthy".to_owned(), self.is_healthy().to_json());
+        object.insert("checks".to_owned(), self.checks.to_json());
+        Json::Object(object)
+    }
+}
+
+impl Display for Diagnosis {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for check in &self.checks {
+            try!(write!(f, "{}\n", check));
+        }
+        let warnings = self.checks.iter().filter(|c| c.warning).count();
+        match self.checks.iter().filter(|c| !c.ok).count() {
+            0 if warnings == 0 => write!(f, "Everything is in place.\n"),
+            0 => write!(f, "Everything required is in place, with {} warnings.\n", warnings),
+            failed => write!(f, "{} of {} checks failed.\n", failed, self.checks.len()),
+        }
+    }
+}
+
+/// Runs `executable` with `args` and returns the first line it printed, which is where tools
+/// put their version.
+fn first_output_line(executable: &Path, args: &[&str], runner: &CommandRunner) -> Option<String> {
+    runner.run_captured(Path::new("."), executable, args)
+        .ok()
+        .and_then(|output| {
+            output.iter().map(|l| l.trim()).find(|l| !l.is_empty()).map(str::to_owned)
+        })
+}
+
+fn installed_platforms(android_home: &Path) -> Vec<String> {
+    let mut platforms: Vec<_> = read_dir(android_home.join("platforms"))
+        .map(|entries| {
+            entries.filter_map(Result::ok)
+                .filter(|e| e.path().join("android.jar").is_file())
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    platforms.sort();
+    platforms
+}
+
+/// Checks everything a build needs, from `ANDROID_HOME` to the debug keystore. Without a
+/// context, the build-tools version isn't pinned and the target platform isn't checked.
+/// Tools are asked for their version with `runner`.
+pub fn diagnose(ctx: Option<&Context>, runner: &CommandRunner) -> Diagnosis {
+    diagnose_at(get_env_as_path("ANDROID_HOME").ok(), ctx, runner)
+}
+
+fn diagnose_at(android_home: Option<PathBuf>,
+               ctx: Option<&Context>,
+               runner: &CommandRunner)
+               -> Diagnosis {
+    let default_context = Context::default();
+    let sdk_context = ctx.unwrap_or(&default_context);
+    let mut checks = Vec::new();
+
+    checks.push(match android_home {
+        Some(ref dir) if dir.is_dir() => Check::passed("ANDROID_HOME", Some(dir), None),
+        Some(ref dir) => {
+            Check::failed("ANDROID_HOME",
+                          format!("'{}' is not a directory", dir.display()),
+                          ANDROID_HOME_HINT)
+        }
+        None => Check::failed("ANDROID_HOME", "not set", ANDROID_HOME_HINT),
+    });
+
+    let javac = find_file_in_path("javac");
+    let java_version = javac.as_ref()
+        .ok()
+        .and_then(|exe| first_output_line(exe, &["-version"], runner));
+    checks.push(Check::of_tool("javac", javac, java_version, JAVA_HINT));
+    for name in &["jar", "jarsigner", "keytool"] {
+        checks.push(Check::of_tool(name, find_file_in_path(name), None, JAVA_HINT));
+    }
+
+    let sdk = match android_home {
+        Some(ref dir) => Sdk::at(dir.to_owned(), sdk_context),
+        None => Sdk::for_context(sdk_context),
+    };
+    let build_tools_version = sdk.as_ref().ok().map(|sdk| sdk.build_tools.version.to_string());
+    match sdk {
+        Ok(ref sdk) => {
+            let version = || build_tools_version.clone();
+            let hint = "Reinstall the selected build-tools with `sdkmanager`, or pin another \
+                        version with 'build_tools_version' in anders.json";
+            checks.push(Check::passed("build-tools", Some(&sdk.build_tools.dir), version()));
+            checks.push(Check::of_tool("aapt", sdk.build_tool("aapt"), version(), hint));
+            checks.push(match Dexer::for_sdk(sdk, sdk_context) {
+                Ok(Dexer::D8(path)) => Check::passed("d8", Some(&path), version()),
+                Ok(Dexer::Dx(path)) => Check::passed("dx", Some(&path), version()),
+                Err(err) => Check::failed("d8 or dx", err, hint),
+            });
+            checks.push(match sdk.build_tool("apksigner") {
+                Ok(path) => Check::passed("apksigner", Some(&path), version()),
+                Err(err) => {
+                    if sdk_context.signer.as_ref().map(|s| s == "apksigner").unwrap_or(false) {
+                        Check::failed("apksigner", err, hint)
+                    } else {
+                        Check::warned("apksigner",
+                                      format!("{}, packages will be signed with jarsigner", err),
+                                      "Install build-tools 24.0.3 or later to sign with \
+                                       apksigner")
+                    }
+                }
+            });
+            let adb = sdk.platform_tool("adb");
+            let adb_version = adb.as_ref()
+                .ok()
+                .and_then(|exe| first_output_line(exe, &["version"], runner));
+            checks.push(Check::of_tool("adb",
+                                       adb,
+                                       adb_version,
+                                       "Install the platform-tools with `sdkmanager \
+                                        platform-tools`"));
+        }
+        Err(ref err) => {
+            checks.push(Check::failed("build-tools",
+                                      err,
+                                      "Install build-tools with `sdkmanager \
+                                       \"build-tools;<version>\"`"))
+        }
+    }
+
+    match (ctx, android_home.as_ref()) {
+        (Some(ctx), Some(android_home)) => {
+            let installed = installed_platforms(android_home);
+            let detail = format!("installed are {}",
+                                 if installed.is_empty() {
+                                     "none".to_owned()
+                                 } else {
+                                     installed.join(", ")
+                                 });
+            let jar = PathBuf::from(android_platform_jar_path(android_home, ctx));
+            checks.push(if jar.is_file() {
+                Check::passed(&format!("platform {}", ctx.target), Some(&jar), None)
+                    .with_detail(detail)
+            } else {
+                Check::failed(&format!("platform {}", ctx.target),
+                              detail,
+                              &format!("Install it with `sdkmanager \"platforms;{}\"`, or change \
+                                        'target' in anders.json",
+                                       ctx.target))
+            });
+        }
+        (None, _) => {
+            checks.push(Check::passed("platform", None, None)
+                .with_detail("not checked, as there is no anders.json".to_owned()))
+        }
+        (Some(ctx), None) => {
+            checks.push(Check::warned(&format!("platform {}", ctx.target),
+                                      "not checked, as ANDROID_HOME is not set",
+                                      ANDROID_HOME_HINT))
+        }
+    }
+
+    checks.push(match debug_keystore_path() {
+        Ok(ref keystore) if keystore.is_file() => {
+            Check::passed("debug keystore", Some(keystore), None)
+        }
+        Ok(ref keystore) => {
+            if find_file_in_path("keytool").is_ok() {
+                Check::passed("debug keystore", Some(keystore), None)
+                    .with_detail("will be created when packaging".to_owned())
+            } else {
+                Check::failed("debug keystore",
+                              format!("'{}' doesn't exist and keytool is missing to create it",
+                                      keystore.display()),
+                              JAVA_HINT)
+            }
+        }
+        Err(err) => Check::failed("debug keystore", err, "Set HOME to your home directory"),
+    });
+
+    Diagnosis {
+        android_home: android_home.map(|dir| dir.display().to_string()),
+        build_tools_version: build_tools_version,
+        checks: checks,
+    }
+}
+
+
+#[test]
+fn test_check_display() {
+    let check = Check::failed("adb", "not found", "install it");
+    assert_eq!(check.to_string(), "[FAIL] adb: not found\n       hint: install it");
+    let check = Check::passed("d8", Some(Path::new("/sdk/d8")), Some("27.0.3".to_owned()));
+    assert_eq!(check.to_string(), "[ok]   d8 at '/sdk/d8' (27.0.3)");
+    let check = Check::warned("apksigner", "not found", "install it");
+    assert!(check.ok);
+    assert_eq!(check.to_string(), "[warn] apksigner: not found\n       hint: install it");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_diagnose_fake_sdk() {
+    use std::fs::{File, create_dir_all, remove_dir_all, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use super::{RecordingRunner, unique_temp_dir};
+
+    let dir = unique_temp_dir("doctor");
+    let (without_platform_tools, without_build_tools) = (dir.join("sdk"), dir.join("empty-sdk"));
+    let build_tools = without_platform_tools.join("build-tools/27.0.3");
+    for path in &[build_tools.join("aapt"),
+                  build_tools.join("d8"),
+                  build_tools.join("apksigner"),
+                  without_platform_tools.join("platforms/android-25/android.jar"),
+                  without_build_tools.join("platforms/android-25/android.jar")] {
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap();
+    }
+    for tool in &["aapt", "d8", "apksigner"] {
+        set_permissions(build_tools.join(tool), Permissions::from_mode(0o755)).unwrap();
+    }
+    let ctx = Context { target: "android-25".to_owned(), ..Context::default() };
+    let runner = RecordingRunner::new();
+    let first = diagnose_at(Some(without_platform_tools.clone()), Some(&ctx), &runner);
+    let second = diagnose_at(Some(without_build_tools.clone()), Some(&ctx), &runner);
+    remove_dir_all(&dir).unwrap();
+
+    let check = |diagnosis: &Diagnosis, name: &str| {
+        diagnosis.checks.iter().find(|c| c.name == name).map(|c| c.ok)
+    };
+    assert_eq!(first.build_tools_version, Some("27.0.3".to_owned()));
+    assert_eq!(check(&first, "ANDROID_HOME"), Some(true));
+    assert_eq!(check(&first, "build-tools"), Some(true));
+    assert_eq!(check(&first, "d8"), Some(true));
+    assert_eq!(check(&first, "platform android-25"), Some(true));
+    assert_eq!(check(&first, "adb"), Some(false));
+    assert!(!first.is_healthy());
+
+    assert_eq!(second.build_tools_version, None);
+    assert_eq!(check(&second, "build-tools"), Some(false));
+    assert_eq!(check(&second, "aapt"), None);
+    assert!(!second.is_healthy());
+    assert_eq!(second.to_json().find("healthy"), Some(&false.to_json()));
+}
@@ -0,0 +1,232 @@
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Duration;
+use rustc_serialize::json::{Json, ToJson};
+use super::{BatchExecutionError, CommandRunner, Context, ExecutionError, Diagnostic, FindError,
+            Sdk};
+use super::logcat::LogLine;
+use super::doctor::Diagnosis;
+use super::{Invocation, Plan};
+
+/// Something that happened while running a command, for consumption by other programs. The
+/// json representation of each event is an object whose `event` field names its kind, and whose
+/// field names are stable.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    StageStarted { stage: String },
+    StageFinished {
+        stage: String,
+        success: bool,
+        duration: Duration,
+    },
+    Command {
+        dir: String,
+        executable: String,
+        args: Vec<String>,
+    },
+    Diagnostic(Diagnostic),
+    Artifact { path: String, fingerprint: String },
+    Log(LogLine),
+    /// The programs a dry run would have run.
+    Plan(Plan),
+    Diagnosis(Diagnosis),
+    /// The error a command failed with, along with the errors that caused it, outermost first.
+    Error { message: String, causes: Vec<String> },
+}
+
+impl Event {
+    pub fn error(err: &Error) -> Event {
+        let mut causes = Vec::new();
+        let mut cursor = err;
+        while let Some(cause) = cursor.cause() {
+            causes.push(cause.to_string());
+            cursor = cause;
+        }
+        Event::Error {
+            message: err.to_string(),
+            causes: causes,
+        }
+    }
+
+    /// Returns the event as json on a single line.
+    pub fn to_json_line(&self) -> String {
+        self.to_json().to_string()
+    }
+}
+
+fn optional<T: ToJson>(value: &Option<T>) -> Json {
+    value.as_ref().map(ToJson::to_json).unwrap_or(Json::Null)
+}
+
+impl ToJson for Invocation {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("dir".to_owned(), self.dir.to_json());
+        object.insert("executable".to_owned(), self.executable.to_json());
+        object.insert("args".to_owned(), self.args.to_json());
+        object.insert("in_process".to_owned(), self.in_process.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for Event {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        let kind = match *self {
+            Event::StageStarted { ref stage } => {
+                object.insert("stage".to_owned(), stage.to_json());
+                "stage-started"
+            }
+            Event::StageFinished { ref stage, success, duration } => {
+                let millis = duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000;
+                object.insert("stage".to_owned(), stage.to_json());
+                object.insert("success".to_owned(), success.to_json());
+                object.insert("duration_ms".to_owned(), millis.to_json());
+                "stage-finished"
+            }
+            Event::Command { ref dir, ref executable, ref args } => {
+                object.insert("dir".to_owned(), dir.to_json());
+                object.insert("executable".to_owned(), executable.to_json());
+                object.insert("args".to_owned(), args.to_json());
+                "command"
+            }
+            Event::Diagnostic(ref diagnostic) => {
+                object.insert("file".to_owned(), optional(&diagnostic.file));
+                object.insert("line".to_owned(), optional(&diagnostic.line));
+                object.insert("column".to_owned(), optional(&diagnostic.column));
+                object.insert("severity".to_owned(), diagnostic.severity.as_str().to_json());
+                object.insert("message".to_owned(), diagnostic.message.to_json());
+                "diagnostic"
+            }
+            Event::Artifact { ref path, ref fingerprint } => {
+                object.insert("path".to_owned(), path.to_json());
+                object.insert("fingerprint".to_owned(), fingerprint.to_json());
+                "artifact"
+            }
+            Event::Log(ref line) => {
+                object.insert("pid".to_owned(), line.pid.to_json());
+                object.insert("priority".to_owned(), line.priority.as_str().to_json());
+                object.insert("tag".to_owned(), line.tag.to_json());
+                object.insert("message".to_owned(), line.message.to_json());
+                "log"
+            }
+            Event::Plan(ref plan) => {
+                object.insert("invocations".to_owned(), plan.invocations.to_json());
+                "plan"
+            }
+            Event::Diagnosis(ref diagnosis) => {
+                if let Json::Object(fields) = diagnosis.to_json() {
+                    object.extend(fields);
+                }
+                "diagnosis"
+            }
+            Event::Error { ref message, ref causes } => {
+                object.insert("message".to_owned(), message.to_json());
+                object.insert("causes".to_owned(), causes.to_json());
+                "error"
+            }
+        };
+        object.insert("event".to_owned(), kind.to_json());
+        Json::Object(object)
+    }
+}
+
+/// Reports a `Command` event for each program before `runner` runs it.
+pub struct ReportingRunner<'a, F>
+    where F: Fn(&Event)
+{
+    pub runner: &'a CommandRunner,
+    pub report: F,
+}
+
+impl<'a, F> ReportingRunner<'a, F>
+    where F: Fn(&Event)
+{
+    fn report_command(&self, at: &Path, executable: &Path, args: &[&str]) {
+        (self.report)(&Event::Command {
+            dir: at.display().to_string(),
+            executable: executable.display().to_string(),
+            args: args.iter().cloned().map(String::from).collect(),
+        });
+    }
+}
+
+impl<'a, F> CommandRunner for ReportingRunner<'a, F>
+    where F: Fn(&Event)
+{
+    fn run(&self, at: &Path, executable: &Path, args: &[&str]) -> Result<(), ExecutionError> {
+        self.report_command(at, executable, args);
+        self.runner.run(at, executable, args)
+    }
+
+    fn run_captured(&self,
+                    at: &Path,
+                    executable: &Path,
+                    args: &[&str])
+                    -> Result<Vec<String>, ExecutionError> {
+        self.report_command(at, executable, args);
+        self.runner.run_captured(at, executable, args)
+    }
+
+    fn run_returning_output(&self,
+                            at: &Path,
+                            executable: &Path,
+                            args: &[&str])
+                            -> Result<Vec<String>, ExecutionError> {
+        self.report_command(at, executable, args);
+        self.runner.run_returning_output(at, executable, args)
+    }
+
+    fn run_streaming(&self,
+                     at: &Path,
+                     executable: &Path,
+                     args: &[&str],
+                     on_line: &mut FnMut(Option<&str>))
+                     -> Result<(), ExecutionError> {
+        self.report_command(at, executable, args);
+        self.runner.run_streaming(at, executable, args, on_line)
+    }
+
+    fn run_in_process(&self,
+                      at: &Path,
+                      executable: &Path,
+                      args: &[&str],
+                      step: &mut FnMut() -> Result<(), BatchExecutionError>)
+                      -> Result<(), BatchExecutionError> {
+        self.report_command(at, executable, args);
+        self.runner.run_in_process(at, executable, args, step)
+    }
+
+    fn sdk(&self, ctx: &Context) -> Result<Sdk, FindError> {
+        self.runner.sdk(ctx)
+    }
+
+    fn find_program(&self, name: &str) -> Result<PathBuf, FindError> {
+        self.runner.find_program(name)
+    }
+
+    fn find_android_program(&self, name: &str) -> Result<PathBuf, FindError> {
+        self.runner.find_android_program(name)
+    }
+
+    fn produces_outputs(&self) -> bool {
+        self.runner.produces_outputs()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.runner.timeout()
+    }
+}
+
+
+#[test]
+fn test_event_json() {
+    let event = Event::StageFinished {
+        stage: "compile".to_owned(),
+        success: true,
+        duration: Duration::from_millis(1500),
+    };
+    assert_eq!(event.to_json_line(),
+               r#"{"duration_ms":1500,"event":"stage-finished","stage":"compile","success":true}"#);
+}
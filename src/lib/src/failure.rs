@@ -0,0 +1,259 @@
+use super::{BatchExecutionError, ContextDeserializationError, ExecutionError, FindError,
+            ResolutionError};
+use super::package::SigningError;
+use super::scaffolding::Error as ScaffoldingError;
+
+/// The category of a failure, which determines the exit code of the `anders` program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Anything not covered by a more specific category, like failing to write a file.
+    Other,
+    /// The command-line arguments were invalid.
+    Usage,
+    /// `anders.json` or the environment it refers to is invalid.
+    Configuration,
+    /// A program or the Android SDK could not be found.
+    MissingTool,
+    /// The sources could not be compiled or dexed.
+    Compilation,
+    /// Creating, signing or verifying the package failed.
+    Packaging,
+    /// Talking to the device or emulator failed.
+    Device,
+    /// The user interrupted a running program with Ctrl-C.
+    Interrupted,
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Failure::Other => 3,
+            Failure::Usage => 4,
+            Failure::Configuration => 10,
+            Failure::MissingTool => 11,
+            Failure::Compilation => 12,
+            Failure::Packaging => 13,
+            Failure::Device => 14,
+            Failure::Interrupted => 130,
+        }
+    }
+
+    /// Returns the category for a program failing within the given command.
+    pub fn of_stage(stage: &str) -> Failure {
+        match stage {
+            "compile" => Failure::Compilation,
+            "package" | "verify" => Failure::Packaging,
+            "launch" | "logcat" => Failure::Device,
+            _ => Failure::Other,
+        }
+    }
+}
+
+impl FindError {
+    pub fn failure(&self) -> Failure {
+        match *self {
+            FindError::Variable { .. } => Failure::Configuration,
+            FindError::Iteration { .. } |
+            FindError::NotFound { .. } |
+            FindError::NoBuildTools { .. } |
+            FindError::BuildToolsVersionNotInstalled { .. } => Failure::MissingTool,
+        }
+    }
+}
+
+impl ExecutionError {
+    pub fn failure(&self, stage: &str) -> Failure {
+        match *self {
+            ExecutionError::Spawn { .. } => Failure::MissingTool,
+            ExecutionError::Exit { .. } |
+            ExecutionError::Timeout { .. } => Failure::of_stage(stage),
+            ExecutionError::Interrupted { .. } => Failure::Interrupted,
+        }
+    }
+
+    /// Returns the exit code of the program, if it ran and exited on its own.
+    pub fn exit_code(&self) -> Option<i32> {
+        match *self {
+            ExecutionError::Exit { ref status, .. } => status.code(),
+            ExecutionError::Spawn { .. } |
+            ExecutionError::Timeout { .. } |
+            ExecutionError::Interrupted { .. } => None,
+        }
+    }
+}
+
+impl ContextDeserializationError {
+    pub fn failure(&self) -> Failure {
+        Failure::Configuration
+    }
+}
+
+impl ScaffoldingError {
+    pub fn failure(&self) -> Failure {
+        match *self {
+            ScaffoldingError::Context(_) => Failure::Configuration,
+            ScaffoldingError::ExistingDirectory(_) => Failure::Usage,
+            ScaffoldingError::Io(..) |
+            ScaffoldingError::Write(..) => Failure::Other,
+        }
+    }
+}
+
+impl BatchExecutionError {
+    /// Returns the category of this error, which happened within the command `stage`.
+    pub fn failure(&self, stage: &str) -> Failure {
+        match *self {
+            BatchExecutionError::Fingerprint(..) |
+            BatchExecutionError::Io(..) |
+            BatchExecutionError::Library(..) |
+            BatchExecutionError::DuplicateClass(..) |
+            BatchExecutionError::Diagnostics(..) => Failure::of_stage(stage),
+            BatchExecutionError::Align(_) => Failure::Packaging,
+            BatchExecutionError::Context(_) => Failure::Configuration,
+            BatchExecutionError::Adb(_) |
+            BatchExecutionError::Device(_) => Failure::Device,
+            BatchExecutionError::Dependency(ref err) => {
+                match *err {
+                    ResolutionError::Repository(ref err) => err.failure(),
+                    ResolutionError::Io(..) => Failure::Other,
+                    ResolutionError::InvalidCoordinate(_) |
+                    ResolutionError::MissingVersion(..) |
+                    ResolutionError::NotFound(..) => Failure::Configuration,
+                }
+            }
+            BatchExecutionError::Signing(ref err) => {
+                match *err {
+                    SigningError::UnsupportedSchemes(_) => Failure::MissingTool,
+                    _ => Failure::Configuration,
+                }
+            }
+            BatchExecutionError::Program(ref err) => err.failure(),
+            BatchExecutionError::Execution(ref err) => err.failure(stage),
+        }
+    }
+
+    /// Returns the exit code of the program that failed, if this error is about one.
+    pub fn program_exit_code(&self) -> Option<i32> {
+        match *self {
+            BatchExecutionError::Execution(ref err) |
+            BatchExecutionError::Diagnostics(_, _, ref err) => err.exit_code(),
+            _ => None,
+        }
+    }
+}
+
+
+#[test]
+fn test_failure_of_missing_tool_depends_on_stage_only_if_it_ran() {
+    use std::path::PathBuf;
+    use std::io;
+
+    let spawn = BatchExecutionError::Execution(ExecutionError::Spawn {
+        executable: PathBuf::from("adb"),
+        at: PathBuf::from("."),
+        err: io::Error::new(io::ErrorKind::NotFound, "not found"),
+    });
+    assert_eq!(spawn.failure("launch"), Failure::MissingTool);
+    assert_eq!(spawn.program_exit_code(), None);
+    let duplicate = BatchExecutionError::DuplicateClass("A.class".to_owned(),
+                                                        PathBuf::from("obj"),
+                                                        PathBuf::from("lib/a.jar"));
+    assert_eq!(duplicate.failure("package").exit_code(), 13);
+    assert_eq!(Failure::of_stage("logcat"), Failure::Device);
+}
+
+
+#[test]
+fn test_failure_of_each_error() {
+    use std::path::PathBuf;
+    use std::env::VarError;
+    use std::io;
+    use std::time::Duration;
+    use rustc_serialize::json::DecoderError;
+    use walkdir::WalkDir;
+    use super::ContextVerificationError;
+
+    fn io_error() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "failed")
+    }
+
+    let iteration = WalkDir::new("does-not-exist").into_iter().next().unwrap().unwrap_err();
+    let variable = FindError::Variable {
+        name: "ANDROID_HOME",
+        err: VarError::NotPresent,
+    };
+    assert_eq!(variable.failure(), Failure::Configuration);
+    let find_errors = [FindError::Iteration {
+                           dir: PathBuf::from("does-not-exist"),
+                           err: iteration,
+                       },
+                       FindError::NotFound {
+                           dir: PathBuf::from("bin"),
+                           name: "javac".to_owned(),
+                       },
+                       FindError::NoBuildTools { dir: PathBuf::from("build-tools") },
+                       FindError::BuildToolsVersionNotInstalled {
+                           version: "1.0.0".to_owned(),
+                           installed: vec!["25.0.2".to_owned()],
+                       }];
+    for err in &find_errors {
+        assert_eq!(err.failure(), Failure::MissingTool, "{:?}", err);
+    }
+
+    let execution_errors = [(ExecutionError::Spawn {
+                                 executable: PathBuf::from("javac"),
+                                 at: PathBuf::from("."),
+                                 err: io_error(),
+                             },
+                             Failure::MissingTool),
+                            (ExecutionError::Timeout {
+                                 executable: PathBuf::from("javac"),
+                                 at: PathBuf::from("."),
+                                 args: Vec::new(),
+                                 timeout: Duration::from_secs(1),
+                                 output: Vec::new(),
+                             },
+                             Failure::Compilation),
+                            (ExecutionError::Interrupted {
+                                 executable: PathBuf::from("javac"),
+                                 at: PathBuf::from("."),
+                             },
+                             Failure::Interrupted)];
+    for &(ref err, failure) in &execution_errors {
+        assert_eq!(err.failure("compile"), failure, "{:?}", err);
+        assert_eq!(err.exit_code(), None);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        let exit = ExecutionError::Exit {
+            executable: PathBuf::from("aapt"),
+            at: PathBuf::from("."),
+            args: Vec::new(),
+            status: ExitStatus::from_raw(2 << 8),
+            output: Vec::new(),
+        };
+        assert_eq!(exit.failure("package"), Failure::Packaging);
+        assert_eq!(exit.exit_code(), Some(2));
+    }
+
+    let deserialization_errors =
+        [ContextDeserializationError::Io(io_error()),
+         ContextDeserializationError::Deserialization(DecoderError::MissingFieldError("package"
+             .to_owned()))];
+    for err in &deserialization_errors {
+        assert_eq!(err.failure(), Failure::Configuration, "{:?}", err);
+    }
+
+    let scaffolding_errors =
+        [(ScaffoldingError::Io(PathBuf::from("app"), io_error()), Failure::Other),
+         (ScaffoldingError::ExistingDirectory(PathBuf::from("app")), Failure::Usage),
+         (ScaffoldingError::Write(PathBuf::from("app/anders.json"), io_error()), Failure::Other),
+         (ScaffoldingError::Context(ContextVerificationError::InvalidProjectName("1".to_owned())),
+          Failure::Configuration)];
+    for &(ref err, failure) in &scaffolding_errors {
+        assert_eq!(err.failure(), failure, "{:?}", err);
+    }
+}
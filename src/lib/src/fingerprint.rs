@@ -0,0 +1,207 @@
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, create_dir_all};
+use std::io::{self, Read, Write};
+use regex::{Regex, quote};
+use rustc_serialize::json::{decode, as_pretty_json};
+use walkdir::WalkDir;
+use super::BatchExecutionError;
+
+pub const BUILD_STATE_FILENAME: &'static str = "obj/anders-build-state.json";
+
+/// Maps paths relative to the project root to the fingerprint of their contents.
+pub type Fingerprints = HashMap<String, String>;
+
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+pub struct BuildState {
+    pub resources: Fingerprints,
+    pub sources: Fingerprints,
+    pub libraries: Fingerprints,
+    pub javac_options: Vec<String>,
+}
+
+impl BuildState {
+    /// Returns the state of the previous build, or an empty one if there was none or it could
+    /// not be read. It is a cache after all, and losing it only costs a full rebuild.
+    pub fn load(at: &Path) -> BuildState {
+        let mut buf = String::new();
+        File::open(at.join(BUILD_STATE_FILENAME))
+            .and_then(|mut f| f.read_to_string(&mut buf))
+            .ok()
+            .and_then(|_| decode(&buf).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, at: &Path) -> Result<(), BatchExecutionError> {
+        let path = at.join(BUILD_STATE_FILENAME);
+        path.parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut f| write!(f, "{}", as_pretty_json(self)))
+            .map_err(|err| BatchExecutionError::Fingerprint(path.to_owned(), err))
+    }
+}
+
+/// A 64 bit FNV-1a hash, which unlike the hasher of the standard library is guaranteed to be
+/// stable across compiler versions.
+pub fn fingerprint_bytes(bytes: &[u8]) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let hash = bytes.iter().fold(OFFSET_BASIS, |h, b| (h ^ *b as u64).wrapping_mul(PRIME));
+    format!("{:016x}", hash)
+}
+
+pub fn fingerprint_file(path: &Path) -> Result<String, io::Error> {
+    let mut buf = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut buf)));
+    Ok(fingerprint_bytes(&buf))
+}
+
+fn relative_key(at: &Path, path: &Path) -> String {
+    path.strip_prefix(at).unwrap_or(path).to_string_lossy().into_owned()
+}
+
+/// Fingerprints all files below `dir` for which `filter` returns true. A missing `dir`
+/// yields no fingerprints.
+pub fn fingerprint_tree<F>(at: &Path,
+                           dir: &str,
+                           filter: F)
+                           -> Result<Fingerprints, (PathBuf, io::Error)>
+    where F: Fn(&Path) -> bool
+{
+    let mut prints = Fingerprints::new();
+    let root = at.join(dir);
+    if !root.is_dir() {
+        return Ok(prints);
+    }
+    for entry in WalkDir::new(&root) {
+        let entry = try!(entry.map_err(|err| {
+            (err.path().unwrap_or(&root).to_owned(), io::Error::from(err))
+        }));
+        if !entry.file_type().is_file() || !filter(entry.path()) {
+            continue;
+        }
+        let print = try!(fingerprint_file(entry.path())
+            .map_err(|err| (entry.path().to_owned(), err)));
+        prints.insert(relative_key(at, entry.path()), print);
+    }
+    Ok(prints)
+}
+
+pub fn fingerprint_files(at: &Path,
+                         paths: &[&Path])
+                         -> Result<Fingerprints, (PathBuf, io::Error)> {
+    let mut prints = Fingerprints::new();
+    for path in paths {
+        let path = at.join(path);
+        let print = try!(fingerprint_file(&path).map_err(|err| (path.to_owned(), err)));
+        prints.insert(relative_key(at, &path), print);
+    }
+    Ok(prints)
+}
+
+/// Returns the keys of all entries in `current` which are new or changed compared to `previous`,
+/// or `None` if entries were removed, as their leftovers can only be dealt with by a full rebuild.
+pub fn changed_entries(previous: &Fingerprints, current: &Fingerprints) -> Option<Vec<String>> {
+    if previous.keys().any(|k| !current.contains_key(k)) {
+        return None;
+    }
+    let mut changed: Vec<_> = current.iter()
+        .filter(|&(k, v)| previous.get(k) != Some(v))
+        .map(|(k, _)| k.to_owned())
+        .collect();
+    changed.sort();
+    Some(changed)
+}
+
+fn type_name_of(java_path: &str) -> Option<&str> {
+    Path::new(java_path).file_stem().and_then(|s| s.to_str())
+}
+
+/// Returns a regex matching java sources which mention any of the types with the given simple
+/// names.
+pub fn type_reference_regex(type_names: &[&str]) -> Regex {
+    let names: Vec<_> = type_names.iter().map(|name| quote(name)).collect();
+    Regex::new(&format!(r"\b(?:{})\b", names.join("|")))
+        .expect("quoted type names to be a valid regex")
+}
+
+/// Extends `changed` java source files by all `candidates` which refer to any of them by name,
+/// directly or through other dependents, as their compiled form might depend on constants or
+/// signatures which just changed.
+pub fn with_dependents(at: &Path,
+                       changed: Vec<String>,
+                       candidates: &[&String])
+                       -> Result<Vec<String>, (PathBuf, io::Error)> {
+    let mut remaining = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        if changed.contains(*candidate) {
+            continue;
+        }
+        let path = at.join(candidate);
+        let mut source = String::new();
+        try!(File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut source))
+            .map_err(|err| (path.to_owned(), err)));
+        remaining.push(((*candidate).to_owned(), source));
+    }
+    let mut result: HashSet<String> = changed.iter().cloned().collect();
+    let mut added = changed;
+    while !added.is_empty() {
+        let type_names: Vec<_> = added.iter().filter_map(|p| type_name_of(p)).collect();
+        if type_names.is_empty() {
+            break;
+        }
+        let references = type_reference_regex(&type_names);
+        let (dependents, others): (Vec<_>, Vec<_>) = remaining.into_iter()
+            .partition(|&(_, ref source)| references.is_match(source));
+        remaining = others;
+        let dependents: Vec<_> = dependents.into_iter().map(|(path, _)| path).collect();
+        result.extend(dependents.iter().cloned());
+        added = dependents;
+    }
+    let mut result: Vec<_> = result.into_iter().collect();
+    result.sort();
+    Ok(result)
+}
+
+#[test]
+fn test_changed_entries() {
+    let prints = |e: &[(&str, &str)]| -> Fingerprints {
+        e.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    };
+    let previous = prints(&[("a", "1"), ("b", "2")]);
+    assert_eq!(changed_entries(&previous, &prints(&[("a", "1"), ("b", "3"), ("c", "4")])),
+               Some(vec!["b".to_owned(), "c".to_owned()]));
+    assert_eq!(changed_entries(&previous, &previous), Some(vec![]));
+    assert_eq!(changed_entries(&previous, &prints(&[("a", "1")])), None);
+}
+
+#[test]
+fn test_type_reference_regex() {
+    let references = type_reference_regex(&["R", "Hello"]);
+    assert!(references.is_match("int x = R.string.hello;"));
+    assert!(references.is_match("Hello.greet();"));
+    assert!(!references.is_match("Resources res;"));
+    assert!(!references.is_match("class HelloWorld {}"));
+}
+
+#[test]
+fn test_with_dependents_is_transitive() {
+    use std::fs::remove_dir_all;
+    use super::unique_temp_dir;
+
+    let at = unique_temp_dir("with-dependents");
+    let sources = [("A.java", "class A { static final int X = 1; }"),
+                   ("B.java", "class B { int x = A.X; }"),
+                   ("C.java", "class C { B b; }"),
+                   ("D.java", "class D {}")];
+    for &(name, source) in &sources {
+        File::create(at.join(name)).and_then(|mut f| f.write_all(source.as_bytes())).unwrap();
+    }
+    let names: Vec<_> = sources.iter().map(|&(name, _)| name.to_owned()).collect();
+    let candidates: Vec<_> = names[1..].iter().collect();
+    let res = with_dependents(&at, vec![names[0].clone()], &candidates);
+    remove_dir_all(&at).unwrap();
+    assert_eq!(res.unwrap(), &names[..3]);
+}
@@ -1,16 +1,353 @@
-use std::path::Path;
-use super::{execute_program_verbosely, BatchExecutionError, Context, find_android_executable,
-            extract_tasks_for, execute_script};
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use regex::Regex;
+use super::{AdbClient, AdbError, CommandRunner, BatchExecutionError, Context, DeviceError,
+            DeviceSelector, ExecutionError, InstallFailure, MANIFEST_FILENAME, extract_tasks_for,
+            execute_script, manifest_package, parse_install_failure, shell_quote};
 
 pub const COMMAND_NAME: &'static str = "launch";
-pub fn launch_application(at: &Path, ctx: &Context) -> Result<(), BatchExecutionError> {
+
+/// The local port debuggers attach to by convention, as used by DDMS.
+pub const DEFAULT_DEBUG_PORT: u16 = 8700;
+
+/// How often to look for the process of the application before giving up on debugging it.
+const PROCESS_LOOKUP_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LaunchOptions {
+    pub device: DeviceSelector,
+    /// Uninstall first, which removes the data of the application.
+    pub clean_install: bool,
+    /// Wait until the activity is displayed. Ignored along with `debug_port`, as the activity
+    /// is only displayed once a debugger attached to it.
+    pub wait: bool,
+    /// String extras passed to the activity with its intent.
+    pub extras: Vec<(String, String)>,
+    /// Have the application wait for a debugger, which can attach on this local port.
+    pub debug_port: Option<u16>,
+    /// Talk to the adb server directly instead of running the `adb` program, unless the runner
+    /// only records programs.
+    pub native_adb: bool,
+}
+
+/// Returns the component name of the activity handling the `MAIN` action in the `LAUNCHER`
+/// category, like `com.app/com.app.Main`.
+pub fn launcher_activity(manifest: &str) -> Option<String> {
+    let package = match manifest_package(manifest) {
+        Some(package) => package,
+        None => return None,
+    };
+    let re_activity = Regex::new(concat!(r"(?s)<activity(?:-alias)?\b([^>]*[^>/])>",
+                                         r"(.*?)</activity(?:-alias)?>"))
+        .expect("valid activity regex");
+    let re_name = Regex::new(r#"android:name\s*=\s*"([^"]+)""#).expect("valid name regex");
+    re_activity.captures_iter(manifest)
+        .filter(|c| {
+            let body = c.at(2).unwrap_or("");
+            body.contains("android.intent.action.MAIN") &&
+            body.contains("android.intent.category.LAUNCHER")
+        })
+        .filter_map(|c| re_name.captures(c.at(1).unwrap_or("")))
+        .filter_map(|c| c.at(1).map(str::to_owned))
+        .next()
+        .map(|name| {
+            let class = if name.starts_with('.') {
+                format!("{}{}", package, name)
+            } else if !name.contains('.') {
+                format!("{}.{}", package, name)
+            } else {
+                name
+            };
+            format!("{}/{}", package, class)
+        })
+}
+
+/// Returns the launcher activity of the application's manifest, or the activity the scaffold
+/// generates if there is none.
+fn activity_to_start(at: &Path, ctx: &Context) -> String {
+    let mut manifest = String::new();
+    File::open(at.join(MANIFEST_FILENAME))
+        .and_then(|mut f| f.read_to_string(&mut manifest))
+        .ok()
+        .and_then(|_| launcher_activity(&manifest))
+        .unwrap_or_else(|| format!("{}/{}.{}", ctx.package, ctx.package, ctx.project))
+}
+
+/// The operations `launch` performs on the device, either by running the `adb` program or by
+/// talking to the adb server directly.
+trait Bridge {
+    /// Installs the package at `apk_path`, relative to the project, failing with
+    /// `DeviceError::Install` if the device refuses it.
+    fn install(&self, apk_path: &str, replace: bool) -> Result<(), BatchExecutionError>;
+    fn uninstall(&self, package: &str) -> Result<(), BatchExecutionError>;
+    fn shell(&self, args: &[&str]) -> Result<Vec<String>, BatchExecutionError>;
+    fn forward(&self, local: &str, remote: &str) -> Result<(), BatchExecutionError>;
+}
+
+fn install_failure(output: &[String]) -> Result<(), BatchExecutionError> {
+    match parse_install_failure(output) {
+        Some((failure, detail)) => Err(DeviceError::Install(failure, detail).into()),
+        None => Ok(()),
+    }
+}
+
+struct AdbProgram<'a> {
+    at: &'a Path,
+    adb_path: PathBuf,
+    device: DeviceSelector,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> AdbProgram<'a> {
+    fn args<'b>(&'b self, args: &[&'b str]) -> Vec<&'b str> {
+        [&self.device.adb_args()[..], args].concat()
+    }
+}
+
+impl<'a> Bridge for AdbProgram<'a> {
+    fn install(&self, apk_path: &str, replace: bool) -> Result<(), BatchExecutionError> {
+        let args = if replace {
+            self.args(&["install", "-r", "-d", apk_path])
+        } else {
+            self.args(&["install", apk_path])
+        };
+        match self.runner.run_returning_output(self.at, &self.adb_path, &args) {
+            // older versions of adb exit successfully even if the installation failed
+            Ok(ref output) => install_failure(output),
+            Err(err) => {
+                if let ExecutionError::Exit { ref output, .. } = err {
+                    try!(install_failure(output));
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    fn uninstall(&self, package: &str) -> Result<(), BatchExecutionError> {
+        try!(self.runner.run(self.at, &self.adb_path, &self.args(&["uninstall", package])));
+        Ok(())
+    }
+
+    fn shell(&self, args: &[&str]) -> Result<Vec<String>, BatchExecutionError> {
+        let args = self.args(&[&["shell"], args].concat());
+        self.runner.run_captured(self.at, &self.adb_path, &args).map_err(Into::into)
+    }
+
+    fn forward(&self, local: &str, remote: &str) -> Result<(), BatchExecutionError> {
+        try!(self.runner.run(self.at, &self.adb_path, &self.args(&["forward", local, remote])));
+        Ok(())
+    }
+}
+
+/// The directory on the device packages are pushed to before installing them.
+const REMOTE_TMP_DIR: &'static str = "/data/local/tmp";
+
+/// Does what `adb` invoked with `args` would do by talking to the adb server with `client`,
+/// obeying the timeout of `runner`, which reports it like a program.
+fn run_natively<T, F>(at: &Path,
+                      args: &[&str],
+                      client: &AdbClient,
+                      runner: &CommandRunner,
+                      operation: F)
+                      -> Result<T, BatchExecutionError>
+    where F: Fn(&AdbClient) -> Result<T, AdbError>
+{
+    let client = AdbClient { timeout: runner.timeout(), ..client.clone() };
+    let mut result = None;
+    try!(runner.run_in_process(at, Path::new("adb"), args, &mut || {
+        result = Some(try!(operation(&client)));
+        Ok(())
+    }));
+    Ok(result.expect("the adb server to be used only by runners producing outputs"))
+}
+
+struct AdbServer<'a> {
+    at: &'a Path,
+    client: AdbClient,
+    device: DeviceSelector,
+    runner: &'a CommandRunner,
+}
+
+impl<'a> AdbServer<'a> {
+    fn run<T, F>(&self, args: &[&str], operation: F) -> Result<T, BatchExecutionError>
+        where F: Fn(&AdbClient) -> Result<T, AdbError>
+    {
+        let args = [&self.device.adb_args()[..], args].concat();
+        run_natively(self.at, &args, &self.client, self.runner, operation)
+    }
+}
+
+impl<'a> Bridge for AdbServer<'a> {
+    fn install(&self, apk_path: &str, replace: bool) -> Result<(), BatchExecutionError> {
+        let local = self.at.join(apk_path);
+        let file_name = local.file_name().expect("package to have a file name").to_string_lossy();
+        let remote = format!("{}/{}", REMOTE_TMP_DIR, file_name);
+        try!(self.run(&["push", apk_path, &remote],
+                      |client| client.push(&self.device, &local, &remote)));
+        let options = if replace { "-r -d" } else { "" };
+        let output = try!(self.shell(&["pm", "install", options, &remote]));
+        self.shell(&["rm", "-f", &remote]).ok();
+        install_failure(&output)
+    }
+
+    fn uninstall(&self, package: &str) -> Result<(), BatchExecutionError> {
+        let output = try!(self.shell(&["pm", "uninstall", package]));
+        match output.iter().find(|line| line.starts_with("Failure")) {
+            Some(failure) => {
+                Err(AdbError::Failed(format!("uninstall {}", package), failure.to_owned()).into())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn shell(&self, args: &[&str]) -> Result<Vec<String>, BatchExecutionError> {
+        let command = args.join(" ");
+        self.run(&[&["shell"], args].concat(),
+                 |client| client.shell(&self.device, &command))
+    }
+
+    fn forward(&self, local: &str, remote: &str) -> Result<(), BatchExecutionError> {
+        self.run(&["forward", local, remote],
+                 |client| client.forward(&self.device, local, remote))
+    }
+}
+
+/// Installs the package on the device chosen by `options`, and starts its launcher activity.
+/// The package is replaced in place to keep the data of the application, unless that is not
+/// possible because it was signed with a different key.
+pub fn launch_application(at: &Path,
+                          ctx: &Context,
+                          options: &LaunchOptions,
+                          runner: &CommandRunner)
+                          -> Result<(), BatchExecutionError> {
     let (before, after) = extract_tasks_for(COMMAND_NAME, ctx);
-    let (adb_path, _) = try!(find_android_executable("adb"));
-    try!(execute_script(before, at));
-    execute_program_verbosely(at, &adb_path, &["-e", "uninstall", &ctx.package]).ok();
-    try!(execute_program_verbosely(at,
-                                   &adb_path,
-                                   &["-e", "install", &format!("bin/{}.apk", ctx.project)]));
-    try!(execute_script(after, at));
+    let dry_run = !runner.produces_outputs();
+    try!(execute_script(before, at, runner));
+    let bridge: Box<Bridge> = if options.native_adb && !dry_run {
+        let client = AdbClient::local();
+        let devices = try!(run_natively(at,
+                                        &["devices", "-l"],
+                                        &client,
+                                        runner,
+                                        AdbClient::devices));
+        let serial = try!(options.device.select(&devices)).serial.clone();
+        Box::new(AdbServer {
+            at: at,
+            client: client,
+            device: DeviceSelector::Serial(serial),
+            runner: runner,
+        })
+    } else {
+        let adb_path = try!(runner.find_android_program("adb"));
+        let device = try!(options.device.resolve(at, &adb_path, runner));
+        Box::new(AdbProgram {
+            at: at,
+            adb_path: adb_path,
+            device: device,
+            runner: runner,
+        })
+    };
+    let apk_path = format!("bin/{}.apk", ctx.project);
+    if options.clean_install {
+        bridge.uninstall(&ctx.package).ok();
+    }
+    match bridge.install(&apk_path, !options.clean_install) {
+        Err(BatchExecutionError::Device(DeviceError::Install(InstallFailure::UpdateIncompatible,
+                                                              _))) if !options.clean_install => {
+            try!(bridge.uninstall(&ctx.package));
+            try!(bridge.install(&apk_path, false));
+        }
+        res => try!(res),
+    }
+
+    let activity = activity_to_start(at, ctx);
+    let mut start_args = vec!["am".to_owned(), "start".to_owned()];
+    if options.debug_port.is_some() {
+        start_args.push("-D".to_owned());
+    } else if options.wait {
+        start_args.push("-W".to_owned());
+    }
+    for &(ref key, ref value) in &options.extras {
+        start_args.push("--es".to_owned());
+        start_args.push(shell_quote(key));
+        start_args.push(shell_quote(value));
+    }
+    start_args.push("-n".to_owned());
+    start_args.push(activity.clone());
+    let output = try!(bridge.shell(&start_args.iter().map(String::as_str).collect::<Vec<_>>()));
+    // `am` exits successfully even if the activity could not be started
+    if let Some(error) = output.iter().find(|line| line.starts_with("Error")) {
+        return Err(DeviceError::Start(activity, error.to_owned()).into());
+    }
+
+    if let Some(port) = options.debug_port {
+        let mut pid: Option<u32> = None;
+        for _ in 0..PROCESS_LOOKUP_ATTEMPTS {
+            // pidof fails while the process doesn't exist yet
+            pid = bridge.shell(&["pidof", &ctx.package])
+                .ok()
+                .and_then(|output| output.iter().filter_map(|l| l.trim().parse().ok()).next());
+            if pid.is_some() || dry_run {
+                break;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        if !dry_run {
+            let pid = try!(pid.ok_or_else(|| DeviceError::NoProcess(ctx.package.clone())));
+            try!(bridge.forward(&format!("tcp:{}", port), &format!("jdwp:{}", pid)));
+        }
+    }
+    try!(execute_script(after, at, runner));
     Ok(())
 }
+
+
+#[test]
+fn test_launcher_activity() {
+    let manifest = r#"<manifest package="com.app">
+<application>
+<activity android:name=".Settings"/>
+<activity android:name=".Main" android:label="@string/app">
+  <intent-filter>
+    <action android:name="android.intent.action.MAIN" />
+    <category android:name="android.intent.category.LAUNCHER" />
+  </intent-filter>
+</activity>
+</application>
+</manifest>"#;
+    assert_eq!(launcher_activity(manifest), Some("com.app/com.app.Main".to_owned()));
+    assert_eq!(launcher_activity(r#"<manifest package="com.app"></manifest>"#), None);
+}
+
+
+#[test]
+fn test_install_with_adb_server() {
+    use super::{SpawningRunner, fake_adb_server};
+
+    let server = fake_adb_server(|command| if command.starts_with("pm install") {
+        "Failure [INSTALL_FAILED_OLDER_SDK: Requires newer sdk version #24]".to_owned()
+    } else {
+        String::new()
+    });
+    let runner = SpawningRunner::default();
+    let bridge = AdbServer {
+        at: Path::new("."),
+        client: server.client.clone(),
+        device: DeviceSelector::Serial("emulator-5554".to_owned()),
+        runner: &runner,
+    };
+    match bridge.install("Cargo.toml", true) {
+        Err(BatchExecutionError::Device(DeviceError::Install(InstallFailure::OlderSdk,
+                                                              detail))) => {
+            assert_eq!(detail, "Requires newer sdk version #24")
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(server.requests
+        .lock()
+        .unwrap()
+        .contains(&"shell:pm install -r -d /data/local/tmp/Cargo.toml".to_owned()));
+}
@@ -3,17 +3,49 @@ extern crate quick_error;
 extern crate regex;
 extern crate walkdir;
 extern crate rustc_serialize;
-extern crate glob;
+extern crate libc;
 
+mod aar;
+mod adb;
 mod context;
+mod dependencies;
+mod device;
+mod dexer;
+mod diagnostics;
+mod events;
+mod failure;
+mod fingerprint;
+mod plan;
 mod process;
+mod runner;
+mod sdk;
 mod shared;
+mod signals;
+mod tool_cache;
+mod zip;
 
 pub mod scaffolding;
 pub mod compile;
 pub mod package;
 pub mod launch;
+pub mod logcat;
+pub mod verify;
+pub mod doctor;
 
+pub use aar::*;
+pub use adb::*;
 pub use context::*;
+pub use dependencies::*;
+pub use device::*;
+pub use dexer::*;
+pub use diagnostics::*;
+pub use events::*;
+pub use failure::*;
+pub use fingerprint::*;
+pub use plan::*;
 pub use process::*;
+pub use runner::*;
+pub use sdk::*;
 pub use shared::*;
+pub use signals::*;
+pub use tool_cache::*;
@@ -0,0 +1,248 @@
+use std::path::Path;
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, Instant};
+use regex::Regex;
+use super::{CommandRunner, BatchExecutionError, Context, DeviceSelector, application_pid};
+
+pub const COMMAND_NAME: &'static str = "logcat";
+
+/// How often to look for the process of the application while it isn't known to run.
+const PROCESS_LOOKUP_INTERVAL_MS: u64 = 2000;
+
+/// The tag of the lines announcing started and stopped processes.
+const ACTIVITY_MANAGER_TAG: &'static str = "ActivityManager";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Verbose,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Fatal,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Verbose
+    }
+}
+
+impl Priority {
+    /// Parses names like `warn` as well as the letters `logcat` prints, like `W`.
+    pub fn parse(priority: &str) -> Option<Priority> {
+        Some(match &*priority.to_lowercase() {
+            "v" | "verbose" => Priority::Verbose,
+            "d" | "debug" => Priority::Debug,
+            "i" | "info" => Priority::Info,
+            "w" | "warn" | "warning" => Priority::Warn,
+            "e" | "error" => Priority::Error,
+            "f" | "a" | "fatal" | "assert" => Priority::Fatal,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Priority::Verbose => "verbose",
+            Priority::Debug => "debug",
+            Priority::Info => "info",
+            Priority::Warn => "warn",
+            Priority::Error => "error",
+            Priority::Fatal => "fatal",
+        }
+    }
+
+    fn letter(&self) -> char {
+        self.as_str().chars().next().expect("non-empty name").to_ascii_uppercase()
+    }
+
+    /// Returns the ANSI escape sequence selecting the color of lines with this priority.
+    pub fn color(&self) -> &'static str {
+        match *self {
+            Priority::Verbose | Priority::Debug => "\x1b[2m",
+            Priority::Info => "\x1b[32m",
+            Priority::Warn => "\x1b[33m",
+            Priority::Error => "\x1b[31m",
+            Priority::Fatal => "\x1b[1;31m",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    pub pid: u32,
+    pub priority: Priority,
+    pub tag: String,
+    pub message: String,
+}
+
+/// Formats the line like `logcat -v brief` does.
+impl Display for LogLine {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f,
+               "{}/{}({}): {}",
+               self.priority.letter(),
+               self.tag,
+               self.pid,
+               self.message)
+    }
+}
+
+/// Returns the regex `parse_log_line` expects, which is costly enough to compile once per stream.
+pub fn log_line_regex() -> Regex {
+    Regex::new(r"^\S+\s+\S+\s+(\d+)\s+\d+\s+([VDIWEFA])\s+(.*?)\s*:(?: (.*))?$")
+        .expect("valid log line regex")
+}
+
+/// Parses lines as printed by `adb logcat -v threadtime`, like
+/// `10-18 12:34:56.789  1234  1250 I ActivityManager: Start proc 4321:com.app/u0a42`.
+pub fn parse_log_line(re_line: &Regex, line: &str) -> Option<LogLine> {
+    re_line.captures(line)
+        .and_then(|c| {
+            let pid = c.at(1).and_then(|pid| pid.parse().ok());
+            let priority = c.at(2).and_then(Priority::parse);
+            match (pid, priority) {
+                (Some(pid), Some(priority)) => {
+                    Some(LogLine {
+                        pid: pid,
+                        priority: priority,
+                        tag: c.at(3).unwrap_or("").to_owned(),
+                        message: c.at(4).unwrap_or("").to_owned(),
+                    })
+                }
+                _ => None,
+            }
+        })
+}
+
+/// Which lines of the application to show.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    /// The lowest priority to show.
+    pub priority: Priority,
+    /// The tags to show, or all if empty.
+    pub tags: Vec<String>,
+}
+
+impl LogFilter {
+    /// Returns the filter configured in the `logcat` section of the context.
+    pub fn from_context(ctx: &Context) -> LogFilter {
+        let config = ctx.logcat.as_ref();
+        LogFilter {
+            priority: config.and_then(|c| c.priority.as_ref())
+                .and_then(|p| Priority::parse(p))
+                .unwrap_or_default(),
+            tags: config.and_then(|c| c.tags.clone()).unwrap_or_default(),
+        }
+    }
+
+    pub fn matches(&self, line: &LogLine) -> bool {
+        line.priority >= self.priority && (self.tags.is_empty() || self.tags.contains(&line.tag))
+    }
+}
+
+/// Returns the id of the process started for `package`, if `line` announces it like
+/// `Start proc 4321:com.app/u0a42 for activity ...`.
+fn started_process(line: &LogLine, package: &str) -> Option<u32> {
+    if line.tag != ACTIVITY_MANAGER_TAG {
+        return None;
+    }
+    line.message
+        .split("Start proc ")
+        .nth(1)
+        .and_then(|process| {
+            let mut parts = process.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(pid), Some(name)) if name.starts_with(&format!("{}/", package)) => {
+                    pid.parse().ok()
+                }
+                _ => None,
+            }
+        })
+}
+
+/// Returns true if `line` announces that process `pid` of `package` is gone, like
+/// `Process com.app (pid 4321) has died`.
+fn is_process_end(line: &LogLine, package: &str, pid: u32) -> bool {
+    line.tag == ACTIVITY_MANAGER_TAG &&
+    line.message.contains(&format!("{} (pid {})", package, pid))
+}
+
+/// Streams the log lines of the application which pass `filter` to `on_line`, until `adb` ends
+/// or an interrupt is received. When the application restarts, the lines of its new process are
+/// streamed instead.
+pub fn stream_log<F>(at: &Path,
+                     ctx: &Context,
+                     device: &DeviceSelector,
+                     filter: &LogFilter,
+                     runner: &CommandRunner,
+                     mut on_line: F)
+                     -> Result<(), BatchExecutionError>
+    where F: FnMut(&LogLine)
+{
+    let adb_path = try!(runner.find_android_program("adb"));
+    let device = try!(device.resolve(at, &adb_path, runner));
+    let device_args = device.adb_args();
+    let mut pid = application_pid(at, &adb_path, &device_args, &ctx.package, runner);
+    let mut last_lookup = Instant::now();
+    let lookup_interval = Duration::from_millis(PROCESS_LOOKUP_INTERVAL_MS);
+    let logcat_args = [&device_args[..], &["logcat", "-v", "threadtime"]].concat();
+    let re_line = log_line_regex();
+    try!(runner.run_streaming(at, &adb_path, &logcat_args, &mut |line| {
+        if pid.is_none() && runner.produces_outputs() && last_lookup.elapsed() >= lookup_interval {
+            pid = application_pid(at, &adb_path, &device_args, &ctx.package, runner);
+            last_lookup = Instant::now();
+        }
+        let line = match line.and_then(|line| parse_log_line(&re_line, line)) {
+            Some(line) => line,
+            None => return,
+        };
+        if let Some(started) = started_process(&line, &ctx.package) {
+            pid = Some(started);
+        }
+        match pid {
+            Some(current) if line.pid == current && filter.matches(&line) => on_line(&line),
+            Some(current) if is_process_end(&line, &ctx.package, current) => {
+                pid = None;
+                last_lookup = Instant::now();
+            }
+            _ => {}
+        }
+    }));
+    Ok(())
+}
+
+/// Returns true if standard output is a terminal, which is when colors should be used.
+#[cfg(unix)]
+pub fn stdout_is_terminal() -> bool {
+    unsafe { ::libc::isatty(::libc::STDOUT_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdout_is_terminal() -> bool {
+    false
+}
+
+
+#[test]
+fn test_parse_log_line() {
+    let re_line = log_line_regex();
+    let start = parse_log_line(&re_line,
+                               "10-18 12:34:56.789  1000  1250 I ActivityManager: Start proc \
+                               4321:com.app/u0a42 for activity com.app/.Main")
+        .unwrap();
+    assert_eq!(start.tag, "ActivityManager");
+    assert_eq!(started_process(&start, "com.app"), Some(4321));
+    assert_eq!(started_process(&start, "com.ap"), None);
+
+    let line = parse_log_line(&re_line, "10-18 12:34:57.001  4321  4321 W Main    : slow start")
+        .unwrap();
+    assert_eq!(line.to_string(), "W/Main(4321): slow start");
+    let filter = LogFilter {
+        priority: Priority::Info,
+        tags: vec!["Main".to_owned()],
+    };
+    assert!(filter.matches(&line));
+    assert_eq!(parse_log_line(&re_line, "--------- beginning of main"), None);
+}
@@ -0,0 +1,228 @@
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Write};
+use super::super::zip::{self, Entry, read_u16, read_u32, write_u16, write_u32, set_u32};
+use std::iter::repeat;
+
+pub const DEFAULT_ALIGNMENT: u32 = 4;
+pub const PAGE_SIZE: u32 = 4096;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum AlignError {
+        Io(path: PathBuf, err: io::Error) {
+            description("A package could not be read or written")
+            display("Failed to read or write package at '{}'", path.display())
+            cause(err)
+        }
+        Misaligned(path: PathBuf, entries: Vec<String>) {
+            description("A package contains unaligned entries")
+            display("Package '{}' has unaligned entries: {}", path.display(), entries.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlignOptions {
+    /// The alignment in bytes of the data of uncompressed entries.
+    pub alignment: u32,
+    /// If true, uncompressed shared libraries are aligned to page boundaries, like `zipalign -p`.
+    pub page_align_shared_libraries: bool,
+}
+
+impl Default for AlignOptions {
+    fn default() -> Self {
+        AlignOptions {
+            alignment: DEFAULT_ALIGNMENT,
+            page_align_shared_libraries: false,
+        }
+    }
+}
+
+impl AlignOptions {
+    fn alignment_of(&self, entry: &Entry) -> u32 {
+        if self.page_align_shared_libraries && entry.name.ends_with(".so") {
+            PAGE_SIZE
+        } else {
+            self.alignment
+        }
+    }
+}
+
+fn local_header_end(data: &[u8], entry: &Entry) -> Result<(usize, usize), io::Error> {
+    let data_offset = try!(entry.data_offset(data));
+    let mut end = data_offset + entry.compressed_size as usize;
+    if entry.flags & zip::FLAG_DATA_DESCRIPTOR != 0 {
+        end += if end + 4 <= data.len() && read_u32(data, end) == zip::DATA_DESCRIPTOR_SIGNATURE {
+            16
+        } else {
+            12
+        };
+    }
+    if end > data.len() {
+        return Err(zip::invalid("entry data exceeds archive"));
+    }
+    Ok((data_offset, end))
+}
+
+/// Rewrites the zip archive in `data` the way `zipalign -f` does: entries are written in the
+/// order of the central directory, and the data of each uncompressed entry is aligned by padding
+/// the extra field of its local header with zeros. Compressed entries are copied verbatim.
+pub fn align(data: &[u8], options: &AlignOptions) -> Result<Vec<u8>, io::Error> {
+    let entries = try!(zip::entries(data));
+    let eocd = try!(zip::find_end_of_central_directory(data));
+    let mut out = Vec::with_capacity(data.len() + entries.len() * options.alignment as usize);
+    let mut central_directory = Vec::new();
+
+    for entry in &entries {
+        let header = entry.local_header_offset as usize;
+        let (data_offset, end) = try!(local_header_end(data, entry));
+        let name_len = read_u16(data, header + 26) as usize;
+        let extra_start = header + zip::LOCAL_HEADER_SIZE + name_len;
+        let extra = &data[extra_start..data_offset];
+
+        let new_header = out.len();
+        let padding = if entry.method == zip::METHOD_STORED {
+            let alignment = options.alignment_of(entry) as usize;
+            let unpadded_data_offset = new_header + zip::LOCAL_HEADER_SIZE + name_len + extra.len();
+            (alignment - unpadded_data_offset % alignment) % alignment
+        } else {
+            0
+        };
+        if extra.len() + padding > 0xffff {
+            return Err(zip::invalid("extra field too large to hold alignment padding"));
+        }
+
+        out.extend_from_slice(&data[header..header + 28]);
+        write_u16(&mut out, (extra.len() + padding) as u16);
+        out.extend_from_slice(&data[header + zip::LOCAL_HEADER_SIZE..extra_start]);
+        out.extend_from_slice(extra);
+        out.extend(repeat(0).take(padding));
+        out.extend_from_slice(&data[data_offset..end]);
+
+        let central_header = central_directory.len();
+        central_directory.extend_from_slice(&data[entry.central_header.clone()]);
+        set_u32(&mut central_directory, central_header + 42, new_header as u32);
+    }
+
+    let central_directory_offset = out.len();
+    out.extend_from_slice(&central_directory);
+    out.extend_from_slice(&data[eocd..eocd + 12]);
+    write_u32(&mut out, central_directory.len() as u32);
+    write_u32(&mut out, central_directory_offset as u32);
+    out.extend_from_slice(&data[eocd + 20..]);
+    Ok(out)
+}
+
+/// Returns the names of all uncompressed entries whose data is not aligned, like `zipalign -c`.
+pub fn misaligned_entries(data: &[u8], options: &AlignOptions) -> Result<Vec<String>, io::Error> {
+    let mut misaligned = Vec::new();
+    for entry in try!(zip::entries(data)) {
+        if entry.method != zip::METHOD_STORED {
+            continue;
+        }
+        if try!(entry.data_offset(data)) % options.alignment_of(&entry) as usize != 0 {
+            misaligned.push(entry.name);
+        }
+    }
+    Ok(misaligned)
+}
+
+pub fn align_file(input: &Path, output: &Path, options: &AlignOptions) -> Result<(), AlignError> {
+    let data = try!(zip::read_file(input).map_err(|err| AlignError::Io(input.to_owned(), err)));
+    let aligned = try!(align(&data, options).map_err(|err| AlignError::Io(input.to_owned(), err)));
+    File::create(output)
+        .and_then(|mut f| f.write_all(&aligned))
+        .map_err(|err| AlignError::Io(output.to_owned(), err))
+}
+
+pub fn verify_alignment(path: &Path, options: &AlignOptions) -> Result<(), AlignError> {
+    let misaligned = try!(zip::read_file(path)
+        .and_then(|data| misaligned_entries(&data, options))
+        .map_err(|err| AlignError::Io(path.to_owned(), err)));
+    if misaligned.is_empty() {
+        Ok(())
+    } else {
+        Err(AlignError::Misaligned(path.to_owned(), misaligned))
+    }
+}
+
+
+#[cfg(test)]
+fn make_zip(entries: &[(&str, &[u8], u16)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut central_directory = Vec::new();
+    for &(name, content, method) in entries {
+        let header = out.len() as u32;
+        let size = content.len() as u32;
+        write_u32(&mut out, zip::LOCAL_HEADER_SIGNATURE);
+        for value in &[20, 0, method, 0, 0] {
+            write_u16(&mut out, *value);
+        }
+        for value in &[0, size, size] {
+            write_u32(&mut out, *value);
+        }
+        write_u16(&mut out, name.len() as u16);
+        write_u16(&mut out, 0);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(content);
+
+        write_u32(&mut central_directory, zip::CENTRAL_DIRECTORY_HEADER_SIGNATURE);
+        for value in &[20, 20, 0, method, 0, 0] {
+            write_u16(&mut central_directory, *value);
+        }
+        for value in &[0, size, size] {
+            write_u32(&mut central_directory, *value);
+        }
+        for value in &[name.len() as u16, 0, 0, 0, 0] {
+            write_u16(&mut central_directory, *value);
+        }
+        write_u32(&mut central_directory, 0);
+        write_u32(&mut central_directory, header);
+        central_directory.extend_from_slice(name.as_bytes());
+    }
+    let central_directory_offset = out.len() as u32;
+    out.extend_from_slice(&central_directory);
+    write_u32(&mut out, zip::END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+    for value in &[0, 0, entries.len() as u16, entries.len() as u16] {
+        write_u16(&mut out, *value);
+    }
+    write_u32(&mut out, central_directory.len() as u32);
+    write_u32(&mut out, central_directory_offset);
+    write_u16(&mut out, 0);
+    out
+}
+
+#[test]
+fn test_align() {
+    let options = AlignOptions { page_align_shared_libraries: true, ..AlignOptions::default() };
+    let input = make_zip(&[("a", b"123", 0),
+                             ("compressed", b"xyz", 8),
+                             ("lib/x86/libfoo.so", b"elf", 0),
+                             ("res/raw/b", b"4", 0)]);
+    assert_eq!(misaligned_entries(&input, &options).unwrap(),
+               vec!["a", "lib/x86/libfoo.so", "res/raw/b"]);
+
+    let aligned = align(&input, &options).unwrap();
+    assert_eq!(misaligned_entries(&aligned, &options).unwrap(), Vec::<String>::new());
+    assert_eq!(align(&aligned, &options).unwrap(), aligned);
+
+    let entries = zip::entries(&aligned).unwrap();
+    assert_eq!(entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>(),
+               vec!["a", "compressed", "lib/x86/libfoo.so", "res/raw/b"]);
+    let so = &entries[2];
+    let so_data = so.data_offset(&aligned).unwrap();
+    assert_eq!(so_data % PAGE_SIZE as usize, 0);
+    assert_eq!(&aligned[so_data..so_data + 3], b"elf");
+}
+
+#[test]
+fn test_align_like_zipalign() {
+    let unaligned = include_bytes!("../../tests/fixtures/unaligned.apk");
+    let aligned = include_bytes!("../../tests/fixtures/aligned.apk");
+    let options = AlignOptions::default();
+    assert_eq!(misaligned_entries(unaligned, &options).unwrap(),
+               vec!["classes.dex", "res/raw/a", "resources.arsc"]);
+    assert_eq!(align(unaligned, &options).unwrap(), &aligned[..]);
+    assert_eq!(misaligned_entries(aligned, &options).unwrap(), Vec::<String>::new());
+}
@@ -0,0 +1,440 @@
+use std::path::{PathBuf, Path};
+use std::collections::HashMap;
+use std::io;
+use std::env;
+use quick_error::ResultExt;
+use walkdir::WalkDir;
+use super::{CommandRunner, BatchExecutionError, Context, ContextVerificationError, Sdk,
+            get_env_as_path, FindError, PasswordSource, ExecutionError, SIGNATURE_SCHEMES, Dexer,
+            extract_tasks_for, execute_script, library_jars, path_argument, LibraryPath,
+            unpack_aars, dependency_artifacts, effective_manifest, aapt_library_args};
+use super::zip;
+use self::align::{AlignOptions, align_file, verify_alignment};
+
+pub mod align;
+
+pub const COMMAND_NAME: &'static str = "package";
+
+const DEBUG_PASSWORD: &'static str = "android";
+const DEBUG_KEY_ALIAS: &'static str = "androiddebugkey";
+const DEBUG_KEYSTORE_NAME: &'static str = "debug.keystore";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum SigningError {
+        MissingConfiguration {
+            description("No signing configuration was provided")
+            display("Release builds require a 'signing' section in the context")
+        }
+        MissingKeystore(path: PathBuf) {
+            description("The keystore could not be found")
+            display("Keystore '{}' does not exist", path.display())
+        }
+        MissingPasswordVariable(name: String) {
+            description("A password environment variable is not set")
+            display("The {} environment variable holding a signing password is not set", name)
+        }
+        MissingPasswordSource(password: &'static str) {
+            description("A signing password has no source")
+            display("The {} password needs either 'env' or 'file' to name its source", password)
+        }
+        MissingPasswordFile(path: PathBuf) {
+            description("A password file could not be found")
+            display("Password file '{}' does not exist", path.display())
+        }
+        UnsupportedSchemes(schemes: Vec<String>) {
+            description("The signer does not support the requested signature schemes")
+            display("jarsigner can only produce v1 signatures, but {} were requested. \
+                     Install build-tools providing apksigner.",
+                    schemes.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Debug,
+    Release,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Password {
+    Literal(String),
+    Env(String),
+    File(PathBuf),
+}
+
+impl Password {
+    /// Returns the password read from `source`, where `password` names it in errors, like `key`.
+    fn from_source(at: &Path,
+                   source: &PasswordSource,
+                   password: &'static str)
+                   -> Result<Password, SigningError> {
+        match (source.env.as_ref(), source.file.as_ref()) {
+            (Some(name), _) => {
+                env::var_os(name)
+                    .map(|_| Password::Env(name.to_owned()))
+                    .ok_or_else(|| SigningError::MissingPasswordVariable(name.to_owned()))
+            }
+            (None, Some(file)) => {
+                let path = at.join(file);
+                if path.is_file() {
+                    Ok(Password::File(path))
+                } else {
+                    Err(SigningError::MissingPasswordFile(path))
+                }
+            }
+            (None, None) => Err(SigningError::MissingPasswordSource(password)),
+        }
+    }
+
+    /// Returns the arguments passing this password to `jarsigner` or `keytool` through `option`,
+    /// e.g. `-storepass`, without revealing it on the command-line unless it is a literal.
+    pub fn jarsigner_args(&self, option: &str) -> Vec<String> {
+        match *self {
+            Password::Literal(ref password) => vec![option.to_owned(), password.to_owned()],
+            Password::Env(ref name) => vec![format!("{}:env", option), name.to_owned()],
+            Password::File(ref path) => {
+                vec![format!("{}:file", option), path.to_string_lossy().into_owned()]
+            }
+        }
+    }
+
+    /// Returns the argument passing this password to `apksigner` options like `--ks-pass`.
+    pub fn apksigner_arg(&self) -> String {
+        match *self {
+            Password::Literal(ref password) => format!("pass:{}", password),
+            Password::Env(ref name) => format!("env:{}", name),
+            Password::File(ref path) => format!("file:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Signer {
+    /// Produces signatures of all schemes, and must run after aligning the package.
+    Apksigner(PathBuf),
+    /// Produces v1 signatures only, and must run before aligning the package.
+    Jarsigner(PathBuf),
+}
+
+impl Signer {
+    /// Returns the signer selected in the context, or `apksigner` if it is available and
+    /// `jarsigner` otherwise.
+    pub fn for_sdk(sdk: &Sdk,
+                   ctx: &Context,
+                   runner: &CommandRunner)
+                   -> Result<Signer, BatchExecutionError> {
+        let jarsigner = || runner.find_program("jarsigner").map(Signer::Jarsigner);
+        let apksigner = || sdk.build_tool("apksigner").map(Signer::Apksigner);
+        let signer = try!(match ctx.signer.as_ref().map(String::as_str) {
+            Some("jarsigner") => jarsigner(),
+            Some("apksigner") => apksigner(),
+            Some(name) => {
+                return Err(ContextVerificationError::InvalidSigner(name.to_owned()).into())
+            }
+            None => apksigner().or_else(|_| jarsigner()),
+        });
+        if let (&Signer::Jarsigner(_), Some(schemes)) = (&signer, ctx.signature_schemes.as_ref()) {
+            if schemes.iter().any(|s| s != "v1") {
+                return Err(SigningError::UnsupportedSchemes(schemes.clone()).into());
+            }
+        }
+        Ok(signer)
+    }
+}
+
+fn apksigner_scheme_args(schemes: Option<&Vec<String>>) -> Vec<String> {
+    let schemes = match schemes {
+        Some(schemes) => schemes,
+        None => return Vec::new(),
+    };
+    let mut args = Vec::new();
+    for scheme in SIGNATURE_SCHEMES {
+        args.push(format!("--{}-signing-enabled", scheme));
+        args.push(schemes.iter().any(|s| s == scheme).to_string());
+    }
+    args
+}
+
+fn sign_with_jarsigner(at: &Path,
+                       runner: &CommandRunner,
+                       jarsigner_path: &Path,
+                       key: &SigningKey,
+                       input: &str,
+                       output: &str)
+                       -> Result<(), ExecutionError> {
+    let password_args: Vec<_> = key.store_password
+        .jarsigner_args("-storepass")
+        .into_iter()
+        .chain(key.key_password.jarsigner_args("-keypass"))
+        .collect();
+    let keystore_path = key.keystore.to_string_lossy();
+    let mut args = vec!["-verbose"];
+    args.extend(password_args.iter().map(String::as_str));
+    args.extend_from_slice(&["-keystore", &keystore_path, "-signedjar", output, input, &key.alias]);
+    runner.run(at, jarsigner_path, &args)
+}
+
+fn sign_with_apksigner(at: &Path,
+                       runner: &CommandRunner,
+                       apksigner_path: &Path,
+                       key: &SigningKey,
+                       schemes: Option<&Vec<String>>,
+                       input: &str,
+                       output: &str)
+                       -> Result<(), ExecutionError> {
+    let keystore_path = key.keystore.to_string_lossy();
+    let store_password = key.store_password.apksigner_arg();
+    let key_password = key.key_password.apksigner_arg();
+    let scheme_args = apksigner_scheme_args(schemes);
+    let mut args = vec!["sign",
+                        "--verbose",
+                        "--ks",
+                        &keystore_path,
+                        "--ks-key-alias",
+                        &key.alias,
+                        "--ks-pass",
+                        &store_password,
+                        "--key-pass",
+                        &key_password];
+    args.extend(scheme_args.iter().map(String::as_str));
+    args.extend_from_slice(&["--out", output, input]);
+    runner.run(at, apksigner_path, &args)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningKey {
+    pub keystore: PathBuf,
+    pub alias: String,
+    pub store_password: Password,
+    pub key_password: Password,
+}
+
+impl SigningKey {
+    /// Returns the well-known android debug key, creating it if needed.
+    pub fn debug(runner: &CommandRunner) -> Result<SigningKey, FindError> {
+        fetch_or_create_android_keystore(runner).map(|keystore| {
+            SigningKey {
+                keystore: keystore,
+                alias: DEBUG_KEY_ALIAS.to_owned(),
+                store_password: Password::Literal(DEBUG_PASSWORD.to_owned()),
+                key_password: Password::Literal(DEBUG_PASSWORD.to_owned()),
+            }
+        })
+    }
+
+    /// Returns the key configured in the `signing` section of the context. The key password
+    /// defaults to the store password.
+    pub fn release(at: &Path, ctx: &Context) -> Result<SigningKey, SigningError> {
+        let signing = try!(ctx.signing.as_ref().ok_or(SigningError::MissingConfiguration));
+        let keystore = at.join(&signing.keystore);
+        if !keystore.is_file() {
+            return Err(SigningError::MissingKeystore(keystore));
+        }
+        let store_password = try!(Password::from_source(at, &signing.store_password, "store"));
+        let key_password = match signing.key_password {
+            Some(ref source) => try!(Password::from_source(at, source, "key")),
+            None => store_password.clone(),
+        };
+        Ok(SigningKey {
+            keystore: keystore,
+            alias: signing.alias.to_owned(),
+            store_password: store_password,
+            key_password: key_password,
+        })
+    }
+
+    pub fn for_profile(at: &Path,
+                       ctx: &Context,
+                       profile: Profile,
+                       runner: &CommandRunner)
+                       -> Result<SigningKey, BatchExecutionError> {
+        Ok(match profile {
+            Profile::Debug => try!(SigningKey::debug(runner)),
+            Profile::Release => try!(SigningKey::release(at, ctx)),
+        })
+    }
+}
+
+/// Returns the path of the android debug keystore, which may not exist yet.
+pub fn debug_keystore_path() -> Result<PathBuf, FindError> {
+    get_env_as_path("HOME").map(|home| home.join(".android").join(DEBUG_KEYSTORE_NAME))
+}
+
+fn fetch_or_create_android_keystore(runner: &CommandRunner) -> Result<PathBuf, FindError> {
+    let keystore = try!(debug_keystore_path());
+    let dir = keystore.parent().expect("keystore to be in a directory").to_owned();
+    if keystore.is_file() {
+        Ok(keystore)
+    } else {
+        let not_found = || {
+            FindError::NotFound {
+                name: DEBUG_KEYSTORE_NAME.to_owned(),
+                dir: dir.to_owned(),
+            }
+        };
+
+        runner.find_program("keytool")
+            .and_then(|keytool_path| {
+                runner.run(Path::new("."),
+                           &keytool_path,
+                           &["-genkey",
+                             "-v",
+                             "-keystore",
+                             &keystore.to_string_lossy(),
+                             "-storepass",
+                             DEBUG_PASSWORD,
+                             "-keypass",
+                             DEBUG_PASSWORD,
+                             "-alias",
+                             DEBUG_KEY_ALIAS,
+                             "-dname",
+                             "CN=Android Debug,O=Android,C=US"])
+                    .map(|_| keystore)
+                    .map_err(|_| not_found())
+            })
+            .map_err(|_| not_found())
+    }
+}
+
+fn is_class_file(name: &str) -> bool {
+    name.ends_with(".class") && !name.starts_with("META-INF/") && name != "module-info.class"
+}
+
+/// Fails if a class is found in more than one of the compiled classes in `obj` and the `jars`, as
+/// the dexer would otherwise bail out with a much less helpful message.
+fn check_for_duplicate_classes(at: &Path, jars: &[PathBuf]) -> Result<(), BatchExecutionError> {
+    let obj_dir = at.join("obj");
+    let mut providers: HashMap<String, PathBuf> = HashMap::new();
+    if obj_dir.is_dir() {
+        for entry in WalkDir::new(&obj_dir) {
+            let entry = try!(entry.map_err(io::Error::from).context(LibraryPath(&obj_dir)));
+            let class = path_argument(&obj_dir, entry.path());
+            if entry.file_type().is_file() && is_class_file(&class) {
+                providers.insert(class, obj_dir.to_owned());
+            }
+        }
+    }
+    for jar in jars {
+        for entry in try!(zip::entries_of(jar).context(LibraryPath(jar))) {
+            if !is_class_file(&entry.name) {
+                continue;
+            }
+            if let Some(first) = providers.get(&entry.name) {
+                return Err(BatchExecutionError::DuplicateClass(entry.name.to_owned(),
+                                                               first.to_owned(),
+                                                               jar.to_owned()));
+            }
+            providers.insert(entry.name, jar.to_owned());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of the signed and aligned package, relative to the project root.
+pub fn apk_path(ctx: &Context) -> String {
+    format!("bin/{}.apk", ctx.project)
+}
+
+pub fn package_application(at: &Path,
+                           ctx: &Context,
+                           profile: Profile,
+                           runner: &CommandRunner)
+                           -> Result<(), BatchExecutionError> {
+    let sdk = try!(runner.sdk(ctx));
+    let dexer = try!(Dexer::for_sdk(&sdk, ctx));
+    let aapt_path = try!(sdk.build_tool("aapt"));
+    let signer = try!(Signer::for_sdk(&sdk, ctx, runner));
+    let key = try!(SigningKey::for_profile(at, ctx, profile, runner));
+
+    let (before, after) = extract_tasks_for(COMMAND_NAME, ctx);
+    try!(execute_script(before, at, runner));
+
+    let dependencies = try!(dependency_artifacts(at, ctx, runner));
+    let aars = try!(unpack_aars(at, ctx, &dependencies, runner));
+    let mut jars = try!(library_jars(at, ctx, &dependencies));
+    for aar in &aars {
+        jars.extend(try!(aar.jars()));
+    }
+    try!(check_for_duplicate_classes(at, &jars));
+    let android_jar_path = sdk.platform_jar_path(ctx);
+    let dexer_args = try!(dexer.args(at, ctx, &android_jar_path, profile, &jars));
+    try!(runner.run(at,
+                    dexer.executable(),
+                    &dexer_args.iter().map(String::as_str).collect::<Vec<_>>()));
+
+    let unsigned_apk_path = format!("bin/{}.unsigned.apk", ctx.project);
+    let manifest = try!(effective_manifest(at, &aars, runner));
+    let library_args = aapt_library_args(at, &aars);
+    let mut aapt_args = vec!["package", "-vf", "-M", &manifest, "-S", "res"];
+    aapt_args.extend(library_args.iter().map(String::as_str));
+    aapt_args.extend_from_slice(&["-I", &android_jar_path, "-F", &unsigned_apk_path, "bin"]);
+    try!(runner.run(at, &aapt_path, &aapt_args));
+
+    let apk_path = apk_path(ctx);
+    let zipalign = |input: &str, output: &str| {
+        let options = AlignOptions { page_align_shared_libraries: true, ..AlignOptions::default() };
+        let alignment = options.alignment.to_string();
+        runner.run_in_process(at,
+                              Path::new("zipalign"),
+                              &["-f", "-p", &alignment, input, output],
+                              &mut || {
+            let output = at.join(output);
+            try!(align_file(&at.join(input), &output, &options));
+            try!(verify_alignment(&output, &options));
+            Ok(())
+        })
+    };
+    match signer {
+        Signer::Jarsigner(ref jarsigner_path) => {
+            let signed_apk_path = format!("bin/{}.signed.apk", ctx.project);
+            try!(sign_with_jarsigner(at,
+                                     runner,
+                                     jarsigner_path,
+                                     &key,
+                                     &unsigned_apk_path,
+                                     &signed_apk_path));
+            try!(zipalign(&signed_apk_path, &apk_path));
+        }
+        Signer::Apksigner(ref apksigner_path) => {
+            let aligned_apk_path = format!("bin/{}.aligned.apk", ctx.project);
+            try!(zipalign(&unsigned_apk_path, &aligned_apk_path));
+            try!(sign_with_apksigner(at,
+                                     runner,
+                                     apksigner_path,
+                                     &key,
+                                     ctx.signature_schemes.as_ref(),
+                                     &aligned_apk_path,
+                                     &apk_path));
+        }
+    }
+    try!(execute_script(after, at, runner));
+    Ok(())
+}
+
+
+#[test]
+fn test_duplicate_classes() {
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use super::unique_temp_dir;
+
+    let at = unique_temp_dir("duplicate-classes");
+    let jar = Path::new("tests/fixtures/library.jar");
+    create_dir_all(at.join("obj/com/app")).unwrap();
+    File::create(at.join("obj/com/app/Main.class")).unwrap();
+    let unique = check_for_duplicate_classes(&at, &[jar.to_owned()]);
+    create_dir_all(at.join("obj/com/lib")).unwrap();
+    File::create(at.join("obj/com/lib/Util.class")).unwrap();
+    let duplicate = check_for_duplicate_classes(&at, &[jar.to_owned()]);
+    remove_dir_all(&at).unwrap();
+    unique.unwrap();
+    match duplicate {
+        Err(BatchExecutionError::DuplicateClass(class, first, second)) => {
+            assert_eq!(class, "com/lib/Util.class");
+            assert_eq!(first, at.join("obj"));
+            assert_eq!(second, jar);
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::Path;
+use rustc_serialize::json::as_pretty_json;
+
+/// A program invocation, as recorded instead of executed by the `RecordingRunner`.
+#[derive(Debug, Clone, PartialEq, Eq, RustcEncodable)]
+pub struct Invocation {
+    pub dir: String,
+    pub executable: String,
+    pub args: Vec<String>,
+    /// True if the work is done within anders instead, which does what the program would.
+    pub in_process: bool,
+}
+
+/// All program invocations recorded by the `RecordingRunner`, in order. `before` and `after`
+/// tasks are invocations of `sh -c <script>`, and work done in-process is recorded as the
+/// invocation of the program doing the same, like `zipalign`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, RustcEncodable)]
+pub struct Plan {
+    pub invocations: Vec<Invocation>,
+}
+
+/// Quotes `arg` for `sh` unless it consists of characters which need no quoting.
+pub fn shell_quote(arg: &str) -> String {
+    let is_plain = !arg.is_empty() &&
+                   arg.chars().all(|c| c.is_alphanumeric() || "-_./:=@,+%".contains(c));
+    if is_plain {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace("'", r"'\''"))
+    }
+}
+
+impl Invocation {
+    pub fn new(dir: &Path, executable: &Path, args: &[&str]) -> Invocation {
+        Invocation {
+            dir: dir.display().to_string(),
+            executable: executable.display().to_string(),
+            args: args.iter().cloned().map(String::from).collect(),
+            in_process: false,
+        }
+    }
+
+    /// Returns the invocation as a line of a shell script, running in a subshell so that
+    /// directory changes don't leak into the next line.
+    pub fn to_shell(&self) -> String {
+        let mut words = vec![shell_quote(&self.executable)];
+        words.extend(self.args.iter().map(|arg| shell_quote(arg)));
+        format!("(cd {} && {})", shell_quote(&self.dir), words.join(" "))
+    }
+}
+
+impl Plan {
+    pub fn to_shell_script(&self) -> String {
+        let mut script = String::from("#!/bin/sh\n");
+        for invocation in &self.invocations {
+            script.push_str(&invocation.to_shell());
+            script.push('\n');
+        }
+        script
+    }
+
+    pub fn to_json(&self) -> String {
+        as_pretty_json(self).to_string()
+    }
+}
+
+
+#[test]
+fn test_plan_to_shell_script() {
+    let invocation = Invocation::new(Path::new("/my app"),
+                                     Path::new("/usr/bin/javac"),
+                                     &["-d", "obj", "it's.java"]);
+    let plan = Plan { invocations: vec![invocation] };
+    assert_eq!(plan.to_shell_script(),
+               "#!/bin/sh\n(cd '/my app' && /usr/bin/javac -d obj 'it'\\''s.java')\n");
+}
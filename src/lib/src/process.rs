@@ -1,10 +1,15 @@
 use std::path::{Path, PathBuf};
 use walkdir::{Error as IterationError, WalkDir};
 use std::env;
-use std::io::{self, Write};
+use std::io::{self, Read, BufRead, BufReader, Write};
 use std::ffi::OsStr;
-use std::process::{ExitStatus, Command};
-use super::BatchExecutionError;
+use std::process::{ExitStatus, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::{BatchExecutionError, TerminalForeground, cached_lookup, is_interrupted,
+            is_interruption, register_child, use_own_process_group, terminate};
 use std::env::consts::EXE_SUFFIX;
 use std::env::split_paths;
 
@@ -29,6 +34,15 @@ quick_error! {
             description("file not found")
             display("An file named '{}' could not be found under '{}'", name, dir.display())
         }
+        NoBuildTools{dir: PathBuf} {
+            description("No build-tools are installed")
+            display("No build-tools version is installed in '{}'", dir.display())
+        }
+        BuildToolsVersionNotInstalled{version: String, installed: Vec<String>} {
+            description("The requested build-tools version is not installed")
+            display("Build-tools version '{}' is not installed, choose one of: {}",
+                    version, installed.join(", "))
+        }
     }
 }
 
@@ -40,33 +54,113 @@ quick_error! {
             display("Failed to start '{}' within '{}'", executable.display(), at.display())
             cause(err)
         }
-        Exit{executable: PathBuf, at: PathBuf, args: Vec<String>, status: ExitStatus} {
+        Exit{executable: PathBuf,
+             at: PathBuf,
+             args: Vec<String>,
+             status: ExitStatus,
+             output: Vec<String>} {
             description("Program exited with non-zero code.")
-            display("Program invocation `{} {}` within '{}' failed with exit code {}",
+            display("Program invocation `{} {}` within '{}' {}{}",
                         executable.display(),
                         &args.join(" "),
                         at.display(),
-                        status.code().expect("exit code when program is done"))
+                        exit_description(status),
+                        output_tail(output))
+        }
+        Timeout{executable: PathBuf,
+                at: PathBuf,
+                args: Vec<String>,
+                timeout: Duration,
+                output: Vec<String>} {
+            description("Program did not finish in time")
+            display("Program invocation `{} {}` within '{}' was stopped as it exceeded the time \
+                     limit of {}{}",
+                        executable.display(),
+                        &args.join(" "),
+                        at.display(),
+                        duration_description(timeout),
+                        output_tail(output))
+        }
+        Interrupted{executable: PathBuf, at: PathBuf} {
+            description("Program was interrupted")
+            display("Program '{}' within '{}' was interrupted", executable.display(), at.display())
         }
     }
 }
 
 pub fn find_file_in_path(name: &str) -> Result<PathBuf, FindError> {
-    get_env_as_path("PATH").and_then(|path| {
-        split_paths(&path)
-            .map(|subpath| find_executable(&subpath, name))
-            .filter_map(Result::ok)
-            .next()
-            .ok_or_else(|| {
-                FindError::NotFound {
-                    name: name.to_owned(),
-                    dir: path,
-                }
-            })
+    cached_lookup(&format!("$PATH|{}", name), || {
+        get_env_as_path("PATH").and_then(|path| {
+            split_paths(&path)
+                .filter_map(|dir| which(&dir, name))
+                .next()
+                .ok_or_else(|| {
+                    FindError::NotFound {
+                        name: name.to_owned(),
+                        dir: path,
+                    }
+                })
+        })
     })
 }
 
+/// The extensions windows considers executable if `PATHEXT` is not set.
+const DEFAULT_PATHEXT: &'static str = ".COM;.EXE;.BAT;.CMD";
+
+/// Returns the file names an executable called `name` may have. Given the value of `PATHEXT`,
+/// these are `name` with each of its extensions, or just `name` if it has one of them already.
+pub fn executable_file_names(name: &str, pathext: Option<&str>) -> Vec<String> {
+    match pathext {
+        None => vec![name.to_owned()],
+        Some(pathext) => {
+            let extensions: Vec<_> = pathext.split(';').filter(|ext| !ext.is_empty()).collect();
+            let lowercase_name = name.to_lowercase();
+            if extensions.iter().any(|ext| lowercase_name.ends_with(&ext.to_lowercase())) {
+                vec![name.to_owned()]
+            } else {
+                extensions.iter().map(|ext| format!("{}{}", name, ext)).collect()
+            }
+        }
+    }
+}
+
+fn platform_pathext() -> Option<String> {
+    if cfg!(windows) {
+        Some(env::var("PATHEXT").unwrap_or_else(|_| DEFAULT_PATHEXT.to_owned()))
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+pub fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Returns the executable called `name` directly within `dir`, like `which` does for each
+/// directory in the `PATH`.
+pub fn which(dir: &Path, name: &str) -> Option<PathBuf> {
+    let pathext = platform_pathext();
+    executable_file_names(name, pathext.as_ref().map(String::as_str))
+        .into_iter()
+        .map(|file_name| dir.join(file_name))
+        .find(|path| is_executable(path))
+}
+
+/// Searches `root` recursively for an executable called `name`, as tools may be anywhere in the
+/// layout of the Android SDK. Use `find_file_in_path` for programs in the `PATH`.
 pub fn find_executable(root: &Path, name: &str) -> Result<PathBuf, FindError> {
+    cached_lookup(&format!("{}|{}", root.display(), name),
+                  || search_executable(root, name))
+}
+
+fn search_executable(root: &Path, name: &str) -> Result<PathBuf, FindError> {
     let name = {
         let mut n = name.to_owned();
         n.push_str(EXE_SUFFIX);
@@ -79,7 +173,9 @@ pub fn find_executable(root: &Path, name: &str) -> Result<PathBuf, FindError> {
                     .file_name()
                     .map(OsStr::to_str)
                     .expect("conversion to OsStr to work") {
-                    Some(file_name) if file_name == name => return Ok(entry.path().to_owned()),
+                    Some(file_name) if file_name == name && is_executable(entry.path()) => {
+                        return Ok(entry.path().to_owned())
+                    }
                     Some(_) | None => continue,
                 }
             }
@@ -123,54 +219,353 @@ pub fn execute_shell_script_verbosely(at_dir: &Path,
     execute_program_verbosely(at_dir, &bash_path, &["-c", script]).map_err(Into::into)
 }
 
+/// Describes how a program ended, which is by a signal if it has no exit code.
+fn exit_description(status: &ExitStatus) -> String {
+    #[cfg(unix)]
+    fn signal(status: &ExitStatus) -> Option<i32> {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    fn signal(_: &ExitStatus) -> Option<i32> {
+        None
+    }
+
+    match (status.code(), signal(status)) {
+        (Some(code), _) => format!("failed with exit code {}", code),
+        (None, Some(signal)) => format!("was terminated by signal {}", signal),
+        (None, None) => format!("failed with {}", status),
+    }
+}
+
+/// The amount of lines of output shown in the message of `ExecutionError::Exit`.
+pub const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Describes `duration` in seconds, or in milliseconds if it is shorter than a second.
+fn duration_description(duration: &Duration) -> String {
+    if duration.as_secs() == 0 {
+        format!("{} ms", duration.subsec_nanos() / 1_000_000)
+    } else {
+        format!("{} seconds", duration.as_secs())
+    }
+}
+
+fn output_tail(output: &[String]) -> String {
+    if output.is_empty() {
+        String::new()
+    } else {
+        let tail = &output[output.len().saturating_sub(OUTPUT_TAIL_LINES)..];
+        format!(", its last lines of output were:\n{}", tail.join("\n"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Log each invocation and stream the output of programs while they run. Programs can read
+    /// from the terminal, like tasks asking for input.
+    Verbose,
+    /// Show the output of a program only if it fails. Programs get no input.
+    Quiet,
+}
+
+/// Connects the standard input of `cmd` according to `verbosity`, and has it run in a process
+/// group of its own. Programs which may read from the terminal take over its foreground while
+/// they run, as they would be stopped otherwise.
+fn set_input(cmd: &mut Command, verbosity: Verbosity) -> TerminalForeground {
+    match verbosity {
+        Verbosity::Verbose => {
+            cmd.stdin(Stdio::inherit());
+            use_own_process_group(cmd, true)
+        }
+        Verbosity::Quiet => {
+            cmd.stdin(Stdio::null());
+            use_own_process_group(cmd, false)
+        }
+    }
+}
+
+/// How long to wait for the output of a program after it exited. Programs may start daemons
+/// which keep its output open, like `adb` does.
+const OUTPUT_GRACE_PERIOD_MS: u64 = 1000;
+
+/// Reads `stream` line by line into `lines`, echoing each line to `echo` if given, and signals
+/// `done` at the end of the stream.
+fn capture_lines<R, W>(stream: R,
+                       mut echo: Option<W>,
+                       lines: Arc<Mutex<Vec<String>>>,
+                       done: Sender<()>)
+    where R: Read + Send + 'static,
+          W: Write + Send + 'static
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buf = Vec::new();
+        while reader.read_until(b'\n', &mut buf).map(|n| n > 0).unwrap_or(false) {
+            if let Some(ref mut echo) = echo {
+                echo.write_all(&buf).ok();
+            }
+            let line = String::from_utf8_lossy(&buf);
+            lines.lock()
+                .expect("no panic while holding the lock")
+                .push(line.trim_end_matches(&['\r', '\n'][..]).to_owned());
+            buf.clear();
+        }
+        done.send(()).ok();
+    });
+}
+
 pub fn execute_program_verbosely(at_dir: &Path,
                                  executable: &Path,
                                  args: &[&str])
                                  -> Result<(), ExecutionError> {
-    write!(io::stderr(),
-           "{} >>> {} {}\n",
-           at_dir.display(),
-           executable.display(),
-           args.join(" "))
-        .ok();
-    let status: ExitStatus = try!(Command::new(executable)
-        .current_dir(at_dir)
+    execute_program(at_dir, executable, args, Verbosity::Verbose, None).map(|_| ())
+}
+
+/// Runs `executable` and returns its output, which is attached to the error if it fails.
+/// Lines of standard output and error are merged in the order they were read, which may differ
+/// from the order they were written, as both are read independently.
+/// It is terminated along with all programs it started if it runs longer than `timeout`, or if
+/// an interrupt is received while `handle_interrupts` is in effect.
+pub fn execute_program(at_dir: &Path,
+                       executable: &Path,
+                       args: &[&str],
+                       verbosity: Verbosity,
+                       timeout: Option<Duration>)
+                       -> Result<Vec<String>, ExecutionError> {
+    let verbose = verbosity == Verbosity::Verbose;
+    if verbose {
+        write!(io::stderr(),
+               "{} >>> {} {}\n",
+               at_dir.display(),
+               executable.display(),
+               args.join(" "))
+            .ok();
+    }
+    let spawn_error = |err| {
+        ExecutionError::Spawn {
+            executable: executable.to_owned(),
+            at: at_dir.to_owned(),
+            err: err,
+        }
+    };
+    let mut cmd = Command::new(executable);
+    cmd.current_dir(at_dir)
         .args(args)
-        .status()
-        .map_err(|err| {
-            ExecutionError::Spawn {
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let _terminal = set_input(&mut cmd, verbosity);
+    let _running = register_child();
+    let mut child = try!(cmd.spawn().map_err(&spawn_error));
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let (done, readers_done) = channel();
+    capture_lines(child.stdout.take().expect("piped stdout"),
+                  if verbose { Some(io::stdout()) } else { None },
+                  lines.clone(),
+                  done.clone());
+    capture_lines(child.stderr.take().expect("piped stderr"),
+                  if verbose { Some(io::stderr()) } else { None },
+                  lines.clone(),
+                  done);
+    let collect_output = || {
+        let grace_period = Duration::from_millis(OUTPUT_GRACE_PERIOD_MS);
+        for _ in 0..2 {
+            if readers_done.recv_timeout(grace_period).is_err() {
+                break;
+            }
+        }
+        lines.lock().expect("no panic while holding the lock").split_off(0)
+    };
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = try!(child.try_wait().map_err(&spawn_error)) {
+            break status;
+        }
+        if is_interrupted() {
+            terminate(&mut child);
+            return Err(ExecutionError::Interrupted {
                 executable: executable.to_owned(),
                 at: at_dir.to_owned(),
-                err: err,
+            });
+        }
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= timeout {
+                terminate(&mut child);
+                return Err(ExecutionError::Timeout {
+                    executable: executable.to_owned(),
+                    at: at_dir.to_owned(),
+                    args: args.iter().cloned().map(String::from).collect(),
+                    timeout: timeout,
+                    output: collect_output(),
+                });
             }
-        }));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
 
+    let output = collect_output();
     if status.success() {
-        Ok(())
-    } else {
-        Err(ExecutionError::Exit {
+        return Ok(output);
+    }
+    if is_interruption(&status) {
+        return Err(ExecutionError::Interrupted {
             executable: executable.to_owned(),
             at: at_dir.to_owned(),
-            args: args.iter().cloned().map(String::from).collect(),
-            status: status,
-        })
+        });
+    }
+    let tail_start = output.len().saturating_sub(OUTPUT_TAIL_LINES);
+    if !verbose && tail_start > 0 {
+        write!(io::stderr(), "{}\n", output[..tail_start].join("\n")).ok();
     }
+    Err(ExecutionError::Exit {
+        executable: executable.to_owned(),
+        at: at_dir.to_owned(),
+        args: args.iter().cloned().map(String::from).collect(),
+        status: status,
+        output: output,
+    })
 }
 
-pub struct ChangeCWD {
-    previous_cwd: PathBuf,
+/// How long `stream_program` waits for a line before telling its caller that none arrived.
+const STREAM_TICK_MS: u64 = 500;
+
+/// Runs `executable` until it ends or an interrupt is received, passing each line of its
+/// standard output to `on_line` as it arrives, and `None` whenever none arrived for a while.
+/// Being interrupted counts as success, as it is the usual way of ending a stream.
+pub fn stream_program(at_dir: &Path,
+                      executable: &Path,
+                      args: &[&str],
+                      verbosity: Verbosity,
+                      on_line: &mut FnMut(Option<&str>))
+                      -> Result<(), ExecutionError> {
+    let spawn_error = |err| {
+        ExecutionError::Spawn {
+            executable: executable.to_owned(),
+            at: at_dir.to_owned(),
+            err: err,
+        }
+    };
+    let mut cmd = Command::new(executable);
+    cmd.current_dir(at_dir)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let _terminal = set_input(&mut cmd, verbosity);
+    let _running = register_child();
+    let mut child = try!(cmd.spawn().map_err(&spawn_error));
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let (done, _) = channel();
+    capture_lines(child.stderr.take().expect("piped stderr"),
+                  None::<io::Stderr>,
+                  errors.clone(),
+                  done);
+    let (line_sender, lines) = channel();
+    let stdout = child.stdout.take().expect("piped stdout");
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        let mut buf = Vec::new();
+        while reader.read_until(b'\n', &mut buf).map(|n| n > 0).unwrap_or(false) {
+            let line = String::from_utf8_lossy(&buf)
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_owned();
+            if line_sender.send(line).is_err() {
+                break;
+            }
+            buf.clear();
+        }
+    });
+
+    loop {
+        if is_interrupted() {
+            terminate(&mut child);
+            return Ok(());
+        }
+        match lines.recv_timeout(Duration::from_millis(STREAM_TICK_MS)) {
+            Ok(line) => on_line(Some(&line)),
+            Err(RecvTimeoutError::Timeout) => on_line(None),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    let status = try!(child.wait().map_err(&spawn_error));
+    if status.success() || is_interruption(&status) {
+        return Ok(());
+    }
+    let output = errors.lock().expect("no panic while holding the lock").split_off(0);
+    Err(ExecutionError::Exit {
+        executable: executable.to_owned(),
+        at: at_dir.to_owned(),
+        args: args.iter().cloned().map(String::from).collect(),
+        status: status,
+        output: output,
+    })
+}
+
+
+#[test]
+fn test_executable_file_names() {
+    assert_eq!(executable_file_names("javac", None), vec!["javac"]);
+    assert_eq!(executable_file_names("javac", Some(".EXE;.BAT;")),
+               vec!["javac.EXE", "javac.BAT"]);
+    assert_eq!(executable_file_names("d8.bat", Some(".EXE;.BAT")), vec!["d8.bat"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_failed_program_output_is_attached_to_error() {
+    let script = "echo out; echo err >&2; exit 3";
+    match execute_program(Path::new("."),
+                          Path::new("sh"),
+                          &["-c", script],
+                          Verbosity::Quiet,
+                          None) {
+        Err(ExecutionError::Exit { output, .. }) => {
+            let mut output = output;
+            output.sort();
+            assert_eq!(output, vec!["err", "out"]);
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
 }
 
-impl ChangeCWD {
-    pub fn into(dir: &Path) -> Result<ChangeCWD, io::Error> {
-        let res = ChangeCWD { previous_cwd: try!(env::current_dir()) };
-        try!(env::set_current_dir(dir));
-        Ok(res)
+#[cfg(unix)]
+#[test]
+fn test_program_is_stopped_after_timeout() {
+    let started = Instant::now();
+    match execute_program(Path::new("."),
+                          Path::new("sh"),
+                          &["-c", "echo started; sleep 10"],
+                          Verbosity::Quiet,
+                          Some(Duration::from_millis(200))) {
+        Err(ExecutionError::Timeout { output, .. }) => assert_eq!(output, vec!["started"]),
+        res => panic!("unexpected result: {:?}", res),
     }
+    assert!(started.elapsed() < Duration::from_secs(5));
 }
 
-impl Drop for ChangeCWD {
-    fn drop(&mut self) {
-        env::set_current_dir(&self.previous_cwd).ok();
+#[cfg(target_os = "linux")]
+#[test]
+fn test_programs_started_by_program_are_stopped_after_timeout() {
+    use std::fs::File;
+
+    let output = match execute_program(Path::new("."),
+                                       Path::new("sh"),
+                                       &["-c", "sleep 10 & echo $!; wait"],
+                                       Verbosity::Verbose,
+                                       Some(Duration::from_millis(200))) {
+        Err(ExecutionError::Timeout { output, .. }) => output,
+        res => panic!("unexpected result: {:?}", res),
+    };
+    let stat_path = format!("/proc/{}/stat", output[0]);
+    let is_running = || {
+        let mut stat = String::new();
+        File::open(&stat_path).and_then(|mut f| f.read_to_string(&mut stat)).is_ok() &&
+        !stat.contains(") Z")
+    };
+    let started = Instant::now();
+    while is_running() && started.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(20));
     }
+    assert!(!is_running());
 }
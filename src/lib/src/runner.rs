@@ -0,0 +1,310 @@
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use super::{BatchExecutionError, Context, ExecutionError, FindError, Invocation, Plan, Sdk,
+            Verbosity, find_file_in_path, find_platform_tool, execute_program, stream_program};
+
+/// Runs the programs of all stages, which allows embedders to run them elsewhere, for example
+/// within a container, and tests to run stages without an SDK.
+pub trait CommandRunner {
+    /// Runs `executable` with `args` within `at`, failing if it can't be started or exits
+    /// unsuccessfully.
+    fn run(&self, at: &Path, executable: &Path, args: &[&str]) -> Result<(), ExecutionError>;
+
+    /// Like `run`, but returns the output instead of showing it, for programs which are asked
+    /// for information, like `adb devices`.
+    fn run_captured(&self,
+                    at: &Path,
+                    executable: &Path,
+                    args: &[&str])
+                    -> Result<Vec<String>, ExecutionError>;
+
+    /// Like `run`, but also returns the output, for programs whose output has to be inspected,
+    /// like `adb install`. By default, the output is captured instead of shown.
+    fn run_returning_output(&self,
+                            at: &Path,
+                            executable: &Path,
+                            args: &[&str])
+                            -> Result<Vec<String>, ExecutionError> {
+        self.run_captured(at, executable, args)
+    }
+
+    /// Runs `executable` until it ends or is interrupted, passing each line of its output to
+    /// `on_line` as it arrives, and `None` whenever none arrived for a while. By default, the
+    /// lines are passed once the program is done.
+    fn run_streaming(&self,
+                     at: &Path,
+                     executable: &Path,
+                     args: &[&str],
+                     on_line: &mut FnMut(Option<&str>))
+                     -> Result<(), ExecutionError> {
+        for line in try!(self.run_captured(at, executable, args)) {
+            on_line(Some(&line));
+        }
+        Ok(())
+    }
+
+    /// Returns the SDK the build-tools and platform of `ctx` are taken from.
+    fn sdk(&self, ctx: &Context) -> Result<Sdk, FindError> {
+        Sdk::for_context(ctx)
+    }
+
+    /// Returns the path to the program `name` in the `PATH`, like `javac`.
+    fn find_program(&self, name: &str) -> Result<PathBuf, FindError> {
+        find_file_in_path(name)
+    }
+
+    /// Returns the path to the program `name` in the Android SDK, like `adb`, preferring the
+    /// `platform-tools`.
+    fn find_android_program(&self, name: &str) -> Result<PathBuf, FindError> {
+        find_platform_tool(name)
+    }
+
+    /// Runs `script` with `sh` within `at`, as done for `before` and `after` tasks.
+    fn run_script(&self, at: &Path, script: &str) -> Result<(), BatchExecutionError> {
+        let sh_path = try!(self.find_program("sh"));
+        self.run(at, &sh_path, &["-c", script]).map_err(Into::into)
+    }
+
+    /// Does work within this process which `executable` invoked with `args` would do otherwise,
+    /// like aligning a package instead of running `zipalign`. `step` is only called if the
+    /// runner produces outputs.
+    fn run_in_process(&self,
+                      _: &Path,
+                      _: &Path,
+                      _: &[&str],
+                      step: &mut FnMut() -> Result<(), BatchExecutionError>)
+                      -> Result<(), BatchExecutionError> {
+        if self.produces_outputs() {
+            step()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// If false, programs don't produce their outputs, so nothing derived from them, like the
+    /// state of incremental builds, may be written either.
+    fn produces_outputs(&self) -> bool {
+        true
+    }
+
+    /// Returns how long the next program may run, which work done within this process, like
+    /// talking to the adb server, should obey as well.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Spawns programs on this machine, capturing their output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpawningRunner {
+    pub verbosity: Verbosity,
+    /// How long each program may run.
+    pub timeout: Option<Duration>,
+    /// When all programs have to be done, usually the end of the stage's time budget.
+    pub deadline: Option<Instant>,
+    /// The time budget `deadline` was derived from, which is reported if a program exceeds it.
+    pub stage_timeout: Option<Duration>,
+}
+
+impl SpawningRunner {
+    /// Returns how long the next program may run, which is zero if the deadline has passed.
+    pub fn effective_timeout(&self) -> Option<Duration> {
+        let remaining = self.deadline.map(|deadline| {
+            let now = Instant::now();
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        });
+        match (self.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        }
+    }
+
+    /// Replaces the effective timeout of a program that timed out by the configured limit it
+    /// exceeded, as the time remaining for the stage means little to users.
+    fn with_configured_limit<T>(&self,
+                                res: Result<T, ExecutionError>)
+                                -> Result<T, ExecutionError> {
+        res.map_err(|err| match err {
+            ExecutionError::Timeout { executable, at, args, timeout, output } => {
+                let limit = match (self.timeout, self.stage_timeout) {
+                    (Some(limit), _) if limit <= timeout => limit,
+                    (_, Some(limit)) => limit,
+                    _ => timeout,
+                };
+                ExecutionError::Timeout {
+                    executable: executable,
+                    at: at,
+                    args: args,
+                    timeout: limit,
+                    output: output,
+                }
+            }
+            err => err,
+        })
+    }
+}
+
+impl Default for SpawningRunner {
+    fn default() -> Self {
+        SpawningRunner {
+            verbosity: Verbosity::Verbose,
+            timeout: None,
+            deadline: None,
+            stage_timeout: None,
+        }
+    }
+}
+
+impl CommandRunner for SpawningRunner {
+    fn run(&self, at: &Path, executable: &Path, args: &[&str]) -> Result<(), ExecutionError> {
+        self.run_returning_output(at, executable, args).map(|_| ())
+    }
+
+    fn run_captured(&self,
+                    at: &Path,
+                    executable: &Path,
+                    args: &[&str])
+                    -> Result<Vec<String>, ExecutionError> {
+        self.with_configured_limit(execute_program(at,
+                                                   executable,
+                                                   args,
+                                                   Verbosity::Quiet,
+                                                   self.effective_timeout()))
+    }
+
+    fn run_returning_output(&self,
+                            at: &Path,
+                            executable: &Path,
+                            args: &[&str])
+                            -> Result<Vec<String>, ExecutionError> {
+        self.with_configured_limit(execute_program(at,
+                                                   executable,
+                                                   args,
+                                                   self.verbosity,
+                                                   self.effective_timeout()))
+    }
+
+    /// Streams are not subject to timeouts, as they usually run until interrupted.
+    fn run_streaming(&self,
+                     at: &Path,
+                     executable: &Path,
+                     args: &[&str],
+                     on_line: &mut FnMut(Option<&str>))
+                     -> Result<(), ExecutionError> {
+        stream_program(at, executable, args, self.verbosity, on_line)
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.effective_timeout()
+    }
+}
+
+/// Records invocations instead of running them, for dry runs and tests.
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    plan: RefCell<Plan>,
+    /// The SDK assumed to be installed, if the one at `$ANDROID_HOME` isn't used.
+    assumed_sdk: Option<Sdk>,
+}
+
+impl RecordingRunner {
+    pub fn new() -> RecordingRunner {
+        RecordingRunner::default()
+    }
+
+    /// Returns a runner which takes all Android tools from `sdk`, and records programs missing
+    /// from the `PATH` by their name, so that stages can be recorded without an installed SDK
+    /// or JDK.
+    pub fn assuming_sdk(sdk: Sdk) -> RecordingRunner {
+        RecordingRunner { assumed_sdk: Some(sdk), ..RecordingRunner::default() }
+    }
+
+    /// Returns everything that was run so far.
+    pub fn into_plan(self) -> Plan {
+        self.plan.into_inner()
+    }
+}
+
+impl CommandRunner for RecordingRunner {
+    fn run(&self, at: &Path, executable: &Path, args: &[&str]) -> Result<(), ExecutionError> {
+        self.plan.borrow_mut().invocations.push(Invocation::new(at, executable, args));
+        Ok(())
+    }
+
+    fn run_captured(&self,
+                    at: &Path,
+                    executable: &Path,
+                    args: &[&str])
+                    -> Result<Vec<String>, ExecutionError> {
+        self.run(at, executable, args).map(|_| Vec::new())
+    }
+
+    fn run_in_process(&self,
+                      at: &Path,
+                      executable: &Path,
+                      args: &[&str],
+                      _: &mut FnMut() -> Result<(), BatchExecutionError>)
+                      -> Result<(), BatchExecutionError> {
+        let mut invocation = Invocation::new(at, executable, args);
+        invocation.in_process = true;
+        self.plan.borrow_mut().invocations.push(invocation);
+        Ok(())
+    }
+
+    fn sdk(&self, ctx: &Context) -> Result<Sdk, FindError> {
+        match self.assumed_sdk {
+            Some(ref sdk) => Ok(sdk.clone()),
+            None => Sdk::for_context(ctx),
+        }
+    }
+
+    fn find_program(&self, name: &str) -> Result<PathBuf, FindError> {
+        match find_file_in_path(name) {
+            Err(_) if self.assumed_sdk.is_some() => Ok(PathBuf::from(name)),
+            res => res,
+        }
+    }
+
+    fn find_android_program(&self, name: &str) -> Result<PathBuf, FindError> {
+        match self.assumed_sdk {
+            Some(ref sdk) => sdk.platform_tool(name),
+            None => find_platform_tool(name),
+        }
+    }
+
+    fn produces_outputs(&self) -> bool {
+        false
+    }
+}
+
+
+#[test]
+fn test_recording_runner() {
+    let (dir, program) = (Path::new("."), Path::new("/nonexistent/program"));
+    let runner = RecordingRunner::new();
+    runner.run(dir, program, &["install", "bin/app.apk"]).unwrap();
+    assert_eq!(runner.into_plan().invocations,
+               vec![Invocation::new(dir, program, &["install", "bin/app.apk"])]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_timeout_reports_configured_limit() {
+    let runner = SpawningRunner {
+        verbosity: Verbosity::Quiet,
+        timeout: Some(Duration::from_secs(60)),
+        deadline: Some(Instant::now() + Duration::from_millis(300)),
+        stage_timeout: Some(Duration::from_millis(300)),
+    };
+    match runner.run(Path::new("."), Path::new("sleep"), &["5"]) {
+        Err(err @ ExecutionError::Timeout { .. }) => {
+            assert!(err.to_string().ends_with("exceeded the time limit of 300 ms"), "{}", err)
+        }
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
@@ -0,0 +1,199 @@
+use std::path::{Path, PathBuf};
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::fs::read_dir;
+use super::{Context, FindError, get_env_as_path, find_executable, which,
+            android_platform_jar_path};
+
+pub const BUILD_TOOLS_DIR: &'static str = "build-tools";
+pub const PLATFORM_TOOLS_DIR: &'static str = "platform-tools";
+
+/// A semantic build-tools version like `25.0.2` or `26.0.0-rc1`, as used for the directory names
+/// below `$ANDROID_HOME/build-tools`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildToolsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// A pre-release suffix like `rc1`, which orders before the release of the same version.
+    pub preview: Option<String>,
+}
+
+impl BuildToolsVersion {
+    pub fn parse(version: &str) -> Option<BuildToolsVersion> {
+        let (numbers, preview) = match version.find('-') {
+            Some(pos) => (&version[..pos], Some(version[pos + 1..].to_owned())),
+            None => (version, None),
+        };
+        let numbers: Vec<_> = numbers.split('.').map(|n| n.parse::<u32>()).collect();
+        if numbers.is_empty() || numbers.len() > 3 || numbers.iter().any(Result::is_err) {
+            return None;
+        }
+        let number = |i: usize| numbers.get(i).and_then(|n| n.clone().ok()).unwrap_or(0);
+        Some(BuildToolsVersion {
+            major: number(0),
+            minor: number(1),
+            patch: number(2),
+            preview: preview,
+        })
+    }
+}
+
+impl Ord for BuildToolsVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.preview, &other.preview) {
+                (&None, &None) => Ordering::Equal,
+                (&None, &Some(_)) => Ordering::Greater,
+                (&Some(_), &None) => Ordering::Less,
+                (&Some(ref a), &Some(ref b)) => {
+                    preview_key(a).cmp(&preview_key(b)).then_with(|| a.cmp(b))
+                }
+            })
+    }
+}
+
+/// Splits a pre-release suffix like `rc10` into its name and number, so that `rc10` orders after
+/// `rc9`.
+fn preview_key(preview: &str) -> (&str, Option<u32>) {
+    let number_start = preview.trim_end_matches(|c: char| c.is_digit(10)).len();
+    (&preview[..number_start], preview[number_start..].parse().ok())
+}
+
+impl PartialOrd for BuildToolsVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Display for BuildToolsVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(write!(f, "{}.{}.{}", self.major, self.minor, self.patch));
+        match self.preview {
+            Some(ref preview) => write!(f, "-{}", preview),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An installed build-tools version along with the directory it lives in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildTools {
+    pub version: BuildToolsVersion,
+    pub dir: PathBuf,
+}
+
+/// The Android SDK at `$ANDROID_HOME`, with all build tools of one build taken from the same
+/// build-tools version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sdk {
+    pub root: PathBuf,
+    pub build_tools: BuildTools,
+}
+
+/// Returns all build-tools versions installed below `root`, sorted from lowest to highest.
+/// Directories whose names are not versions are ignored.
+pub fn installed_build_tools(root: &Path) -> Result<Vec<BuildTools>, FindError> {
+    let dir = root.join(BUILD_TOOLS_DIR);
+    let entries = try!(read_dir(&dir).map_err(|_| {
+        FindError::NoBuildTools { dir: dir.to_owned() }
+    }));
+    let mut installed: Vec<_> = entries.filter_map(Result::ok)
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| {
+            e.file_name().to_str().and_then(BuildToolsVersion::parse).map(|version| {
+                BuildTools {
+                    version: version,
+                    dir: e.path(),
+                }
+            })
+        })
+        .collect();
+    installed.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(installed)
+}
+
+impl Sdk {
+    /// Returns the SDK at `$ANDROID_HOME` using the build-tools version pinned in the context,
+    /// or the highest installed one.
+    pub fn for_context(ctx: &Context) -> Result<Sdk, FindError> {
+        get_env_as_path("ANDROID_HOME").and_then(|root| Sdk::at(root, ctx))
+    }
+
+    pub fn at(root: PathBuf, ctx: &Context) -> Result<Sdk, FindError> {
+        let mut installed = try!(installed_build_tools(&root));
+        let build_tools = match ctx.build_tools_version {
+            Some(ref pinned) => {
+                let version = BuildToolsVersion::parse(pinned);
+                match installed.iter().position(|bt| Some(&bt.version) == version.as_ref()) {
+                    Some(pos) => installed.swap_remove(pos),
+                    None => {
+                        return Err(FindError::BuildToolsVersionNotInstalled {
+                            version: pinned.to_owned(),
+                            installed: installed.iter().map(|bt| bt.version.to_string()).collect(),
+                        })
+                    }
+                }
+            }
+            None => {
+                try!(installed.pop().ok_or_else(|| {
+                    FindError::NoBuildTools { dir: root.join(BUILD_TOOLS_DIR) }
+                }))
+            }
+        };
+        Ok(Sdk {
+            root: root,
+            build_tools: build_tools,
+        })
+    }
+
+    /// Returns the path to a tool of the selected build-tools version, never falling back to
+    /// other versions.
+    pub fn build_tool(&self, name: &str) -> Result<PathBuf, FindError> {
+        which(&self.build_tools.dir, name)
+            .ok_or_else(|| {
+                FindError::NotFound {
+                    name: name.to_owned(),
+                    dir: self.build_tools.dir.to_owned(),
+                }
+            })
+    }
+
+    /// Returns the path to a tool like `adb` from the `platform-tools`, or from anywhere in the
+    /// SDK for older layouts.
+    pub fn platform_tool(&self, name: &str) -> Result<PathBuf, FindError> {
+        platform_tool_in(&self.root, name)
+    }
+
+    pub fn platform_jar_path(&self, ctx: &Context) -> String {
+        android_platform_jar_path(&self.root, ctx)
+    }
+}
+
+fn platform_tool_in(root: &Path, name: &str) -> Result<PathBuf, FindError> {
+    which(&root.join(PLATFORM_TOOLS_DIR), name)
+        .map(Ok)
+        .unwrap_or_else(|| find_executable(root, name))
+}
+
+/// Returns the path to a tool like `adb` of the SDK at `ANDROID_HOME`, as `Sdk::platform_tool`
+/// does, without requiring build-tools.
+pub fn find_platform_tool(name: &str) -> Result<PathBuf, FindError> {
+    get_env_as_path("ANDROID_HOME").and_then(|root| platform_tool_in(&root, name))
+}
+
+
+#[test]
+fn test_build_tools_version_ordering() {
+    let parse = |v| BuildToolsVersion::parse(v).expect("valid version");
+    assert!(parse("25.0.10") > parse("25.0.9"));
+    assert!(parse("26.0.0") > parse("26.0.0-rc2"));
+    assert!(parse("26.0.0-rc2") > parse("26.0.0-rc1"));
+    assert!(parse("26.0.0-rc10") > parse("26.0.0-rc9"));
+    assert!(parse("27") > parse("26.0.3"));
+    assert_eq!(BuildToolsVersion::parse("android-4.4W"), None);
+    assert_eq!(BuildToolsVersion::parse("android-25"), None);
+    assert_eq!(BuildToolsVersion::parse("nonsense"), None);
+    assert_eq!(parse("28.0.0-rc1").to_string(), "28.0.0-rc1");
+}
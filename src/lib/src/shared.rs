@@ -1,7 +1,13 @@
 use std::path::{Path, PathBuf};
 use std::io;
-use super::Context;
-use super::process::{FindError, ExecutionError, execute_shell_script_verbosely};
+use std::fs::read_dir;
+use std::iter::once;
+use super::{Context, ContextVerificationError, ResolutionError};
+use quick_error::ResultExt;
+use super::package::SigningError;
+use super::package::align::AlignError;
+use super::process::{FindError, ExecutionError};
+use super::{AdbError, CommandRunner, DeviceError, Diagnostic, format_diagnostics};
 
 pub fn android_platform_jar_path(android_home_dir: &Path, ctx: &Context) -> String {
     format!("{}/platforms/{}/android.jar",
@@ -9,6 +15,50 @@ pub fn android_platform_jar_path(android_home_dir: &Path, ctx: &Context) -> Stri
             ctx.target)
 }
 
+pub const LIBRARY_DIR: &'static str = "lib";
+
+/// Returns the archives with the given extension directly within `lib` and all library directories
+/// of the context, sorted by name within each directory, in the order the directories are declared,
+/// followed by the matching `dependencies`, as returned by `dependency_artifacts`.
+pub fn library_archives(at: &Path,
+                        ctx: &Context,
+                        dependencies: &[PathBuf],
+                        extension: &str)
+                        -> Result<Vec<PathBuf>, BatchExecutionError> {
+    let has_extension = |path: &Path| path.extension().map(|ext| ext == extension).unwrap_or(false);
+    let declared_dirs = ctx.library_dirs.iter().flat_map(|dirs| dirs.iter()).map(String::as_str);
+    let mut archives = Vec::new();
+    for dir in once(LIBRARY_DIR).chain(declared_dirs) {
+        let dir = at.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut archives_in_dir = Vec::new();
+        for entry in try!(read_dir(&dir).context(LibraryPath(&dir))) {
+            let path = try!(entry.context(LibraryPath(&dir))).path();
+            if has_extension(&path) {
+                archives_in_dir.push(path);
+            }
+        }
+        archives_in_dir.sort();
+        archives.extend(archives_in_dir);
+    }
+    archives.extend(dependencies.iter().filter(|path| has_extension(path)).cloned());
+    Ok(archives)
+}
+
+pub fn library_jars(at: &Path,
+                    ctx: &Context,
+                    dependencies: &[PathBuf])
+                    -> Result<Vec<PathBuf>, BatchExecutionError> {
+    library_archives(at, ctx, dependencies, "jar")
+}
+
+/// Returns `path` relative to `at` if possible, for use as argument to programs running in `at`.
+pub fn path_argument(at: &Path, path: &Path) -> String {
+    path.strip_prefix(at).unwrap_or(path).to_string_lossy().into_owned()
+}
+
 pub fn extract_tasks_for<'a>(command: &'static str,
                              ctx: &'a Context)
                              -> (Option<&'a String>, Option<&'a String>) {
@@ -19,22 +69,75 @@ pub fn extract_tasks_for<'a>(command: &'static str,
 }
 
 
-pub fn execute_script(script: Option<&String>, at: &Path) -> Result<(), BatchExecutionError> {
+pub fn execute_script(script: Option<&String>,
+                      at: &Path,
+                      runner: &CommandRunner)
+                      -> Result<(), BatchExecutionError> {
     if let Some(script) = script {
-        try!(execute_shell_script_verbosely(at, script));
+        try!(runner.run_script(at, script));
     };
     Ok(())
 }
 
 
 
+pub struct LibraryPath<'a>(pub &'a Path);
+
+/// Returns a new directory for a test, unique to the test process.
+#[cfg(test)]
+pub fn unique_temp_dir(name: &str) -> PathBuf {
+    use std::env::temp_dir;
+    use std::fs::create_dir_all;
+    use std::process;
+
+    let dir = temp_dir().join(format!("anders-{}-{}", name, process::id()));
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum BatchExecutionError {
-        ChangeCurrentWorkingDir(path: PathBuf, err: io::Error) {
-            description("Failed to change current working directory")
-            display("Failed to chagne current working directory to '{}'", path.display())
-            context(path: &'a Path, err: io::Error) -> (path.to_path_buf(), err)
+        Fingerprint(path: PathBuf, err: io::Error) {
+            description("The build state could not be saved")
+            display("Failed to write build state at '{}'", path.display())
+            cause(err)
+        }
+        Io(path: PathBuf, err: io::Error) {
+            description("A file of the build could not be read or written")
+            display("Failed to access '{}'", path.display())
+            from(e: (PathBuf, io::Error)) -> (e.0, e.1)
+            cause(err)
+        }
+        Library(path: PathBuf, err: io::Error) {
+            description("A library could not be read")
+            display("Failed to read library at '{}'", path.display())
+            context(p: LibraryPath<'a>, err: io::Error) -> (p.0.to_path_buf(), err)
+            cause(err)
+        }
+        DuplicateClass(class: String, first: PathBuf, second: PathBuf) {
+            description("A class was provided more than once")
+            display("Class '{}' is provided by both '{}' and '{}'",
+                    class, first.display(), second.display())
+        }
+        Dependency(err: ResolutionError) {
+            description("Dependencies could not be resolved")
+            from()
+            cause(err)
+        }
+        Signing(err: SigningError) {
+            description("The signing key could not be obtained")
+            from()
+            cause(err)
+        }
+        Align(err: AlignError) {
+            description("A package could not be aligned")
+            from()
+            cause(err)
+        }
+        Context(err: ContextVerificationError) {
+            description("The context is invalid")
+            from()
             cause(err)
         }
         Program(err: FindError) {
@@ -42,6 +145,22 @@ quick_error! {
             from()
             cause(err)
         }
+        Adb(err: AdbError) {
+            description("Talking to the adb server failed")
+            from()
+            cause(err)
+        }
+        Device(err: DeviceError) {
+            description("The device could not be used")
+            from()
+            cause(err)
+        }
+        Diagnostics(tool: String, diagnostics: Vec<Diagnostic>, err: ExecutionError) {
+            description("A program reported problems with its input")
+            display("{} failed with {} problem(s):\n{}",
+                    tool, diagnostics.len(), format_diagnostics(diagnostics))
+            cause(err)
+        }
         Execution(err: ExecutionError) {
             description("A program failed to execute")
             from()
@@ -49,3 +168,28 @@ quick_error! {
         }
     }
 }
+
+
+#[test]
+fn test_library_jars_order() {
+    use std::fs::{File, create_dir_all, remove_dir_all};
+
+    let at = unique_temp_dir("library-jars");
+    for path in &["lib/b.jar", "lib/a.jar", "lib/notes.txt", "vendor/c.jar", "other/d.jar"] {
+        let path = at.join(path);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap();
+    }
+    let ctx = Context {
+        library_dirs: Some(vec!["vendor".to_owned(), "missing".to_owned()]),
+        ..Context::default()
+    };
+    let dependencies = [PathBuf::from("/repo/e.jar"), PathBuf::from("/repo/f.aar")];
+    let jars = library_jars(&at, &ctx, &dependencies);
+    remove_dir_all(&at).unwrap();
+    assert_eq!(jars.unwrap(),
+               vec![at.join("lib/a.jar"),
+                    at.join("lib/b.jar"),
+                    at.join("vendor/c.jar"),
+                    PathBuf::from("/repo/e.jar")]);
+}
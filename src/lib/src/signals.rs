@@ -0,0 +1,167 @@
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+/// How long a program may take to exit after being asked to terminate, before it is killed.
+pub const TERMINATION_GRACE_PERIOD_MS: u64 = 3000;
+
+// not declared by the libc crate in the used version
+#[cfg(unix)]
+extern "C" {
+    fn tcsetpgrp(fd: ::libc::c_int, pgrp: ::libc::pid_t) -> ::libc::c_int;
+}
+
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+static RUNNING_CHILDREN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Interrupts the running programs if there are any, and ends this process like the default
+/// handler would otherwise, as no one would notice the interrupt.
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: ::libc::c_int) {
+    if RUNNING_CHILDREN.load(Ordering::SeqCst) > 0 {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        return;
+    }
+    unsafe {
+        ::libc::signal(::libc::SIGINT, ::libc::SIG_DFL);
+        ::libc::raise(::libc::SIGINT);
+    }
+}
+
+/// Makes Ctrl-C terminate the running program and all programs it started, and makes it fail
+/// with `ExecutionError::Interrupted`. Programs run in their own process group, so without this,
+/// an interrupt would only end the calling process and leave them running.
+/// While no program runs, Ctrl-C ends the calling process right away.
+#[cfg(unix)]
+pub fn handle_interrupts() {
+    unsafe {
+        ::libc::signal(::libc::SIGINT,
+                       on_interrupt as extern "C" fn(::libc::c_int) as ::libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn handle_interrupts() {}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Marks a program as running until it is dropped. Once no program runs anymore, a received
+/// interrupt is forgotten, so it doesn't stop the programs started later.
+pub struct RunningChild(());
+
+pub fn register_child() -> RunningChild {
+    RUNNING_CHILDREN.fetch_add(1, Ordering::SeqCst);
+    RunningChild(())
+}
+
+impl Drop for RunningChild {
+    fn drop(&mut self) {
+        if RUNNING_CHILDREN.fetch_sub(1, Ordering::SeqCst) == 1 {
+            INTERRUPTED.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Gives the terminal back to the process group of this process once dropped, if a program took
+/// it over.
+pub struct TerminalForeground(bool);
+
+/// Has the program started by `cmd` run in a process group of its own, which allows to terminate
+/// all the programs it starts in turn. With `foreground`, the group takes over the terminal this
+/// process is in the foreground of until the returned value is dropped, so that its programs can
+/// read from it, and Ctrl-C reaches them instead of this process.
+#[cfg(unix)]
+pub fn use_own_process_group(cmd: &mut Command, foreground: bool) -> TerminalForeground {
+    use std::io;
+    use std::os::unix::process::CommandExt;
+    use libc::{STDIN_FILENO, SIGTTOU, SIG_DFL, SIG_IGN, getpgrp, getpid, isatty, setpgid, signal,
+               tcgetpgrp};
+
+    let foreground = foreground &&
+                     unsafe { isatty(STDIN_FILENO) == 1 && tcgetpgrp(STDIN_FILENO) == getpgrp() };
+    unsafe {
+        cmd.pre_exec(move || {
+            if setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if foreground {
+                // the new group is in the background, which may not take over the terminal
+                signal(SIGTTOU, SIG_IGN);
+                let res = tcsetpgrp(STDIN_FILENO, getpid());
+                signal(SIGTTOU, SIG_DFL);
+                if res != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    TerminalForeground(foreground)
+}
+
+#[cfg(not(unix))]
+pub fn use_own_process_group(_: &mut Command, _: bool) -> TerminalForeground {
+    TerminalForeground(false)
+}
+
+impl Drop for TerminalForeground {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        use libc::{STDIN_FILENO, SIGTTOU, SIG_IGN, getpgrp, signal};
+
+        if self.0 {
+            unsafe {
+                let previous = signal(SIGTTOU, SIG_IGN);
+                tcsetpgrp(STDIN_FILENO, getpgrp());
+                signal(SIGTTOU, previous);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
+
+/// Returns true if a program ended because of Ctrl-C, which it receives itself while it is in
+/// the foreground of the terminal.
+#[cfg(unix)]
+pub fn is_interruption(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal() == Some(::libc::SIGINT)
+}
+
+#[cfg(not(unix))]
+pub fn is_interruption(_: &ExitStatus) -> bool {
+    false
+}
+
+/// Asks the process group of `child` to terminate, and kills it if it is still running after the
+/// grace period.
+#[cfg(unix)]
+pub fn terminate(child: &mut Child) {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let group = -(child.id() as ::libc::pid_t);
+    unsafe {
+        ::libc::kill(group, ::libc::SIGTERM);
+    }
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(TERMINATION_GRACE_PERIOD_MS) {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    unsafe {
+        ::libc::kill(group, ::libc::SIGKILL);
+    }
+    child.wait().ok();
+}
+
+#[cfg(not(unix))]
+pub fn terminate(child: &mut Child) {
+    child.kill().ok();
+    child.wait().ok();
+}
@@ -0,0 +1,270 @@
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env::{self, split_paths};
+use std::fs::{File, create_dir_all, metadata};
+use std::io::{Read, Write};
+use std::time::UNIX_EPOCH;
+use rustc_serialize::json::{decode, as_pretty_json};
+use super::{FindError, get_env_as_path, is_executable};
+#[cfg(test)]
+use super::unique_temp_dir;
+
+/// The location of the tool cache relative to `$HOME`.
+pub const TOOL_CACHE_PATH: &'static str = ".anders/tool-cache.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolCacheMode {
+    /// Every lookup searches the file system.
+    Disabled,
+    /// Lookups are remembered for the lifetime of the process.
+    InMemory,
+    /// Lookups are also remembered across invocations, in `TOOL_CACHE_PATH`.
+    OnDisk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+struct CachedTool {
+    path: String,
+    /// The modification times of the directory containing the tool and all `PATH` directories
+    /// searched before it, in nanoseconds since the epoch, or 0 if they didn't exist. If one
+    /// changes, tools may have been added or removed, possibly shadowing this one.
+    dir_mtimes: BTreeMap<String, u64>,
+}
+
+/// Tools found by previous lookups, valid only for the `PATH` and `ANDROID_HOME` they were
+/// found with.
+#[derive(Debug, Default, PartialEq, Eq, RustcDecodable, RustcEncodable)]
+struct ToolCache {
+    path_env: Option<String>,
+    android_home: Option<String>,
+    tools: BTreeMap<String, CachedTool>,
+}
+
+struct State {
+    mode: ToolCacheMode,
+    cache: Option<ToolCache>,
+}
+
+thread_local!(static STATE: RefCell<State> = RefCell::new(State {
+    mode: ToolCacheMode::InMemory,
+    cache: None,
+}));
+
+pub fn set_tool_cache_mode(mode: ToolCacheMode) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.mode = mode;
+        state.cache = None;
+    })
+}
+
+pub fn tool_cache_mode() -> ToolCacheMode {
+    STATE.with(|state| state.borrow().mode)
+}
+
+fn dir_mtime(dir: &Path) -> u64 {
+    metadata(dir)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl CachedTool {
+    /// Remembers the tool at `path`, found by searching the directories of `path_env` in order
+    /// if it is in one of them.
+    fn new(path: &Path, path_env: Option<&str>) -> CachedTool {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut dirs: Vec<_> = path_env.map(|p| split_paths(p).collect()).unwrap_or_default();
+        match dirs.iter().position(|d| d == dir) {
+            Some(pos) => dirs.truncate(pos),
+            None => dirs.clear(),
+        }
+        dirs.push(dir.to_owned());
+        CachedTool {
+            path: path.to_string_lossy().into_owned(),
+            dir_mtimes: dirs.iter()
+                .map(|d| (d.to_string_lossy().into_owned(), dir_mtime(d)))
+                .collect(),
+        }
+    }
+
+    /// A cached tool is valid as long as it is still executable and no directory searched before
+    /// its own changed, which might mean it is shadowed now.
+    fn is_valid(&self) -> bool {
+        is_executable(Path::new(&self.path)) &&
+        self.dir_mtimes.iter().all(|(dir, mtime)| dir_mtime(Path::new(dir)) == *mtime)
+    }
+}
+
+fn disk_path() -> Option<PathBuf> {
+    get_env_as_path("HOME").ok().map(|home| home.join(TOOL_CACHE_PATH))
+}
+
+impl ToolCache {
+    fn for_environment() -> ToolCache {
+        ToolCache {
+            path_env: env::var("PATH").ok(),
+            android_home: env::var("ANDROID_HOME").ok(),
+            tools: BTreeMap::new(),
+        }
+    }
+
+    fn matches_environment(&self) -> bool {
+        self.path_env == env::var("PATH").ok() &&
+        self.android_home == env::var("ANDROID_HOME").ok()
+    }
+
+    /// Returns the cache stored on disk, or an empty one if there is none, it can't be read or
+    /// was written for another environment.
+    fn load() -> ToolCache {
+        let mut buf = String::new();
+        disk_path()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|mut f| f.read_to_string(&mut buf).ok())
+            .and_then(|_| decode::<ToolCache>(&buf).ok())
+            .and_then(|cache| if cache.matches_environment() { Some(cache) } else { None })
+            .unwrap_or_else(ToolCache::for_environment)
+    }
+
+    /// Failing to save is ignored, as it only costs another lookup next time.
+    fn save(&self) {
+        if let Some(path) = disk_path() {
+            path.parent()
+                .map_or(Ok(()), create_dir_all)
+                .and_then(|_| File::create(&path))
+                .and_then(|mut f| write!(f, "{}", as_pretty_json(self)))
+                .ok();
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<PathBuf> {
+        let is_valid = match self.tools.get(key) {
+            Some(tool) => tool.is_valid(),
+            None => return None,
+        };
+        if is_valid {
+            self.tools.get(key).map(|tool| PathBuf::from(&tool.path))
+        } else {
+            self.tools.remove(key);
+            None
+        }
+    }
+}
+
+fn with_cache<F, T>(f: F) -> T
+    where F: FnOnce(ToolCacheMode, &mut ToolCache) -> T
+{
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let mode = state.mode;
+        let is_stale = state.cache.as_ref().map(|c| !c.matches_environment()).unwrap_or(true);
+        if is_stale {
+            state.cache = Some(match mode {
+                ToolCacheMode::OnDisk => ToolCache::load(),
+                ToolCacheMode::InMemory | ToolCacheMode::Disabled => ToolCache::for_environment(),
+            });
+        }
+        f(mode, state.cache.as_mut().expect("cache to be initialized"))
+    })
+}
+
+/// Returns the tool remembered under `key`, or looks it up with `find` and remembers it.
+/// A remembered tool is looked up again if it doesn't exist anymore, or the directory containing
+/// it or a `PATH` directory searched before it was changed.
+pub fn cached_lookup<F>(key: &str, find: F) -> Result<PathBuf, FindError>
+    where F: FnOnce() -> Result<PathBuf, FindError>
+{
+    if tool_cache_mode() == ToolCacheMode::Disabled {
+        return find();
+    }
+    if let Some(path) = with_cache(|_, cache| cache.get(key)) {
+        return Ok(path);
+    }
+    let path = try!(find());
+    with_cache(|mode, cache| {
+        let tool = CachedTool::new(&path, cache.path_env.as_ref().map(String::as_str));
+        cache.tools.insert(key.to_owned(), tool);
+        if mode == ToolCacheMode::OnDisk {
+            cache.save();
+        }
+    });
+    Ok(path)
+}
+
+
+
+/// Creates an empty file at `path` which counts as executable.
+#[cfg(test)]
+fn create_executable(path: &Path) {
+    File::create(path).unwrap();
+    #[cfg(unix)]
+    {
+        use std::fs::{set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+        set_permissions(path, Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+#[test]
+fn test_cached_lookup_is_invalidated_when_tool_is_gone() {
+    use std::fs::{remove_file, remove_dir_all};
+
+    let dir = unique_temp_dir("tool-cache-test");
+    let tool = dir.join("tool");
+    create_executable(&tool);
+    let not_found = || {
+        Err(FindError::NotFound {
+            name: "tool".to_owned(),
+            dir: dir.clone(),
+        })
+    };
+
+    assert_eq!(cached_lookup("test|tool", || Ok(tool.clone())).unwrap(), tool);
+    assert_eq!(cached_lookup("test|tool", &not_found).unwrap(), tool);
+    remove_file(&tool).unwrap();
+    assert!(cached_lookup("test|tool", &not_found).is_err());
+    remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_cached_tool_is_invalidated_when_no_longer_executable() {
+    use std::fs::{remove_dir_all, set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = unique_temp_dir("tool-cache-executable-test");
+    let tool = dir.join("tool");
+    create_executable(&tool);
+    let cached = CachedTool::new(&tool, None);
+    assert!(cached.is_valid());
+    set_permissions(&tool, Permissions::from_mode(0o644)).unwrap();
+    let is_valid = cached.is_valid();
+    remove_dir_all(&dir).unwrap();
+    assert!(!is_valid);
+}
+
+#[test]
+fn test_cached_tool_is_invalidated_when_earlier_path_dir_changes() {
+    use std::fs::remove_dir_all;
+
+    let dir = unique_temp_dir("tool-cache-path-test");
+    let (first, second, last) = (dir.join("first"), dir.join("second"), dir.join("last"));
+    for d in &[&first, &second, &last] {
+        create_dir_all(d).unwrap();
+    }
+    let tool = second.join("tool");
+    create_executable(&tool);
+    let path_env = env::join_paths(&[&first, &second, &last]).unwrap();
+    let cached = CachedTool::new(&tool, path_env.to_str());
+    assert_eq!(cached.dir_mtimes.len(), 2);
+    assert!(cached.is_valid());
+    File::create(last.join("tool")).unwrap();
+    assert!(cached.is_valid());
+    File::create(first.join("tool")).unwrap();
+    let is_valid = cached.is_valid();
+    remove_dir_all(&dir).unwrap();
+    assert!(!is_valid);
+}
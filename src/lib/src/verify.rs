@@ -0,0 +1,17 @@
+use std::path::Path;
+use super::{CommandRunner, BatchExecutionError, Context, extract_tasks_for, execute_script};
+
+pub const COMMAND_NAME: &'static str = "verify";
+
+pub fn verify_application(at: &Path,
+                          ctx: &Context,
+                          runner: &CommandRunner)
+                          -> Result<(), BatchExecutionError> {
+    let apksigner_path = try!(try!(runner.sdk(ctx)).build_tool("apksigner"));
+    let (before, after) = extract_tasks_for(COMMAND_NAME, ctx);
+    try!(execute_script(before, at, runner));
+    let apk_path = format!("bin/{}.apk", ctx.project);
+    try!(runner.run(at, &apksigner_path, &["verify", "--verbose", "--print-certs", &apk_path]));
+    try!(execute_script(after, at, runner));
+    Ok(())
+}
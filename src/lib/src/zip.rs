@@ -0,0 +1,143 @@
+use std::path::Path;
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::Range;
+
+pub const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+pub const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+pub const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x02014b50;
+pub const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+pub const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+pub const LOCAL_HEADER_SIZE: usize = 30;
+pub const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+pub const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+pub const METHOD_STORED: u16 = 0;
+const MAX_COMMENT_SIZE: usize = 0xffff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub flags: u16,
+    pub method: u16,
+    pub compressed_size: u32,
+    pub uncompressed_size: u32,
+    pub local_header_offset: u32,
+    /// The location of the raw central directory header of this entry within the archive.
+    pub central_header: Range<usize>,
+}
+
+pub fn read_u16(data: &[u8], at: usize) -> u16 {
+    data[at] as u16 | (data[at + 1] as u16) << 8
+}
+
+pub fn read_u32(data: &[u8], at: usize) -> u32 {
+    read_u16(data, at) as u32 | (read_u16(data, at + 2) as u32) << 16
+}
+
+pub fn write_u16(out: &mut Vec<u8>, value: u16) {
+    out.push(value as u8);
+    out.push((value >> 8) as u8);
+}
+
+pub fn write_u32(out: &mut Vec<u8>, value: u32) {
+    write_u16(out, value as u16);
+    write_u16(out, (value >> 16) as u16);
+}
+
+pub fn set_u32(data: &mut [u8], at: usize, value: u32) {
+    let mut buf = Vec::with_capacity(4);
+    write_u32(&mut buf, value);
+    data[at..at + 4].copy_from_slice(&buf);
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the offset of the end-of-central-directory record.
+pub fn find_end_of_central_directory(data: &[u8]) -> Result<usize, io::Error> {
+    if data.len() < END_OF_CENTRAL_DIRECTORY_SIZE {
+        return Err(invalid("file too small to be a zip archive"));
+    }
+    let last = data.len() - END_OF_CENTRAL_DIRECTORY_SIZE;
+    let first = last.saturating_sub(MAX_COMMENT_SIZE);
+    (first..last + 1)
+        .rev()
+        .find(|&ofs| read_u32(data, ofs) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or_else(|| invalid("end of central directory not found"))
+}
+
+/// Parses the central directory of the zip archive in `data`, returning its entries in the order
+/// they are stored.
+pub fn entries(data: &[u8]) -> Result<Vec<Entry>, io::Error> {
+    let eocd = try!(find_end_of_central_directory(data));
+    let count = read_u16(data, eocd + 10) as usize;
+    let mut ofs = read_u32(data, eocd + 16) as usize;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if ofs + CENTRAL_DIRECTORY_HEADER_SIZE > data.len() ||
+           read_u32(data, ofs) != CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+            return Err(invalid("corrupt central directory"));
+        }
+        let name_len = read_u16(data, ofs + 28) as usize;
+        let extra_len = read_u16(data, ofs + 30) as usize;
+        let comment_len = read_u16(data, ofs + 32) as usize;
+        let name_start = ofs + CENTRAL_DIRECTORY_HEADER_SIZE;
+        if name_start + name_len > data.len() {
+            return Err(invalid("corrupt central directory"));
+        }
+        let end = name_start + name_len + extra_len + comment_len;
+        entries.push(Entry {
+            name: String::from_utf8_lossy(&data[name_start..name_start + name_len]).into_owned(),
+            flags: read_u16(data, ofs + 8),
+            method: read_u16(data, ofs + 10),
+            compressed_size: read_u32(data, ofs + 20),
+            uncompressed_size: read_u32(data, ofs + 24),
+            local_header_offset: read_u32(data, ofs + 42),
+            central_header: ofs..end,
+        });
+        ofs = end;
+    }
+    Ok(entries)
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let mut buf = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut buf)));
+    Ok(buf)
+}
+
+pub fn entries_of(path: &Path) -> Result<Vec<Entry>, io::Error> {
+    read_file(path).and_then(|data| entries(&data))
+}
+
+impl Entry {
+    /// Returns the offset at which the data of this entry starts, right after its local header.
+    pub fn data_offset(&self, data: &[u8]) -> Result<usize, io::Error> {
+        let ofs = self.local_header_offset as usize;
+        if ofs + LOCAL_HEADER_SIZE > data.len() || read_u32(data, ofs) != LOCAL_HEADER_SIGNATURE {
+            return Err(invalid("corrupt local file header"));
+        }
+        Ok(ofs + LOCAL_HEADER_SIZE + read_u16(data, ofs + 26) as usize +
+           read_u16(data, ofs + 28) as usize)
+    }
+}
+
+
+#[test]
+fn test_entries() {
+    let data = include_bytes!("../tests/fixtures/unaligned.apk");
+    let parsed = entries(data).unwrap();
+    assert_eq!(parsed.iter().map(|e| &*e.name).collect::<Vec<_>>(),
+               vec!["AndroidManifest.xml",
+                    "classes.dex",
+                    "lib/x86/libfoo.so",
+                    "res/raw/a",
+                    "resources.arsc"]);
+    assert_eq!(parsed[0].method, 8);
+    assert_eq!((parsed[0].compressed_size, parsed[0].uncompressed_size), (39, 136));
+    assert_eq!(parsed[1].method, METHOD_STORED);
+    assert_eq!(parsed[1].local_header_offset, 88);
+    assert_eq!(parsed[1].data_offset(data).unwrap(), 88 + LOCAL_HEADER_SIZE + "classes.dex".len());
+    assert!(entries(&data[..data.len() - 1]).is_err());
+}
@@ -1,7 +1,7 @@
 extern crate anders;
 
 mod context {
-    use anders::{Context, ContextVerificationError};
+    use anders::{Context, ContextVerificationError, Signing, PasswordSource, Java};
 
     fn ctx_from_target(name: &str) -> Context {
         Context {
@@ -58,4 +58,83 @@ mod context {
         assert_eq!(ctx_from_project(name).verify(),
                    Err(ContextVerificationError::InvalidProjectName(name.to_owned())));
     }
+
+    fn ctx_from_store_password(source: PasswordSource) -> Context {
+        Context {
+            project: "name".to_owned(),
+            target: "target".to_owned(),
+            signing: Some(Signing {
+                keystore: "release.keystore".to_owned(),
+                alias: "release".to_owned(),
+                store_password: source,
+                key_password: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_likes_passwords_from_the_environment() {
+        let source = PasswordSource { env: Some("STORE_PASSWORD".to_owned()), file: None };
+        assert_eq!(ctx_from_store_password(source).verify(), Ok(()));
+    }
+
+    #[test]
+    fn it_rejects_password_sources_without_env_or_file() {
+        assert_eq!(ctx_from_store_password(PasswordSource::default()).verify(),
+                   Err(ContextVerificationError::InvalidPasswordSource("store_password"
+                       .to_owned())));
+    }
+
+    #[test]
+    fn it_rejects_password_sources_with_both_env_and_file() {
+        let source = PasswordSource {
+            env: Some("STORE_PASSWORD".to_owned()),
+            file: Some("password.txt".to_owned()),
+        };
+        assert_eq!(ctx_from_store_password(source).verify(),
+                   Err(ContextVerificationError::InvalidPasswordSource("store_password"
+                       .to_owned())));
+    }
+
+    #[test]
+    fn it_rejects_unknown_signature_schemes() {
+        let ctx = Context {
+            project: "name".to_owned(),
+            target: "target".to_owned(),
+            signature_schemes: Some(vec!["v2".to_owned(), "v4".to_owned()]),
+            ..Default::default()
+        };
+        assert_eq!(ctx.verify(),
+                   Err(ContextVerificationError::InvalidSignatureScheme("v4".to_owned())));
+    }
+
+    fn ctx_from_language_level(dexer: Option<&str>, level: &str) -> Context {
+        Context {
+            project: "name".to_owned(),
+            target: "target".to_owned(),
+            dexer: dexer.map(str::to_owned),
+            java: Some(Java { source: Some(level.to_owned()), ..Default::default() }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_likes_java_8_with_d8() {
+        assert_eq!(ctx_from_language_level(Some("d8"), "1.8").verify(), Ok(()));
+        assert_eq!(ctx_from_language_level(None, "8").verify(), Ok(()));
+    }
+
+    #[test]
+    fn it_rejects_java_8_with_dx() {
+        assert_eq!(ctx_from_language_level(Some("dx"), "1.8").verify(),
+                   Err(ContextVerificationError::UnsupportedLanguageLevel("1.8".to_owned(),
+                                                                          "1.7".to_owned())));
+    }
+
+    #[test]
+    fn it_rejects_invalid_language_levels() {
+        assert_eq!(ctx_from_language_level(None, "eight").verify(),
+                   Err(ContextVerificationError::InvalidLanguageLevel("eight".to_owned())));
+    }
 }